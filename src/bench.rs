@@ -0,0 +1,103 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Instant;
+
+use crate::domain::extract_entries;
+use crate::output::{LineEnding, OutputFormat};
+use crate::pipeline::write_blocklist_file;
+use crate::whitelist::WhitelistManager;
+
+#[derive(Debug, Serialize)]
+pub struct PhaseTiming {
+    pub phase: &'static str,
+    pub millis: f64,
+    pub items: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub files: usize,
+    pub input_bytes: usize,
+    pub phases: Vec<PhaseTiming>,
+}
+
+fn timed<T>(f: impl FnOnce() -> T) -> (T, f64) {
+    let start = Instant::now();
+    let value = f();
+    (value, start.elapsed().as_secs_f64() * 1000.0)
+}
+
+/// Run the parse, dedup, whitelist and write phases over local fixture files,
+/// timing each phase separately, and print the timings as JSON. No network
+/// I/O is performed.
+pub fn run_bench(files: &[String], whitelist: &WhitelistManager) -> Result<()> {
+    let mut contents = Vec::with_capacity(files.len());
+    for file in files {
+        let content = std::fs::read(file).with_context(|| format!("Failed to read {file}"))?;
+        contents.push(content);
+    }
+    let input_bytes = contents.iter().map(Vec::len).sum();
+
+    let (keys, parse_ms) = timed(|| {
+        let mut keys = Vec::new();
+        for content in &contents {
+            let text = String::from_utf8_lossy(content);
+            keys.extend(
+                text.lines()
+                    .flat_map(|line| extract_entries(line, true))
+                    .map(|entry| entry.to_key()),
+            );
+        }
+        keys
+    });
+    let parsed = keys.len();
+
+    let (unique, dedup_ms) = timed(|| keys.into_iter().collect::<HashSet<String>>());
+    let deduped = unique.len();
+
+    let ((filtered, _), filter_ms) = timed(|| whitelist.filter_domains(&unique));
+
+    let out_path = std::env::temp_dir().join(format!("pihole-bench-{}.txt", std::process::id()));
+    let (written, write_ms) = timed(|| {
+        write_blocklist_file(
+            &out_path,
+            &filtered,
+            None,
+            OutputFormat::Hosts,
+            &[],
+            LineEnding::Lf,
+        )
+    });
+    written?;
+    let _ = std::fs::remove_file(&out_path);
+
+    let report = BenchReport {
+        files: files.len(),
+        input_bytes,
+        phases: vec![
+            PhaseTiming {
+                phase: "parse",
+                millis: parse_ms,
+                items: parsed,
+            },
+            PhaseTiming {
+                phase: "dedup",
+                millis: dedup_ms,
+                items: deduped,
+            },
+            PhaseTiming {
+                phase: "whitelist",
+                millis: filter_ms,
+                items: filtered.len(),
+            },
+            PhaseTiming {
+                phase: "write",
+                millis: write_ms,
+                items: filtered.len(),
+            },
+        ],
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+
+use crate::domain::{extract_entry, format_num};
+
+/// Domains from `--blacklist` that are forced into the production lists even
+/// when no source list has them. They are added before whitelist filtering,
+/// so a whitelist entry still removes them.
+pub struct BlacklistManager {
+    /// Exact domains and `||domain^` wildcard keys, as the parsed lists store them.
+    entries: HashSet<String>,
+}
+
+impl BlacklistManager {
+    /// Unlike the whitelist, a missing blacklist is an error: the file was
+    /// named explicitly on the command line.
+    pub fn load(blacklist_file: &str) -> Result<Self> {
+        let bytes = std::fs::read(blacklist_file)
+            .with_context(|| format!("Failed to load blacklist {blacklist_file}"))?;
+        Ok(Self::parse(&String::from_utf8_lossy(&bytes)))
+    }
+
+    /// Build a blacklist from the contents of a blacklist file: one exact
+    /// domain, `*.domain` or `||domain^` wildcard, or hosts line per line.
+    pub fn parse(content: &str) -> Self {
+        let mut entries = HashSet::new();
+        for (line_num, line) in content.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            match extract_entry(trimmed, true) {
+                Some(entry) => {
+                    entries.insert(entry.to_key());
+                }
+                None => warn!(
+                    "Invalid blacklist entry on line {}: {trimmed}",
+                    line_num + 1
+                ),
+            }
+        }
+        info!("Loaded blacklist: {} entries", format_num(entries.len()));
+        Self { entries }
+    }
+
+    /// Add every entry to each of `categories`, creating missing ones.
+    /// Returns how many entries were new across all of them.
+    pub fn apply(
+        &self,
+        category_domains: &mut HashMap<String, HashSet<String>>,
+        categories: &[String],
+    ) -> usize {
+        let mut added = 0;
+        for cat in categories {
+            let domains = category_domains.entry(cat.clone()).or_default();
+            let before = domains.len();
+            domains.extend(self.entries.iter().cloned());
+            added += domains.len() - before;
+        }
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::whitelist::WhitelistManager;
+
+    #[test]
+    fn parse_accepts_exact_wildcard_and_hosts_entries() {
+        let blacklist = BlacklistManager::parse(
+            "# forced\nmissed.com\n*.tracker.net\n||ads.org^\n0.0.0.0 host.io\nnot a domain\n",
+        );
+        let mut entries: Vec<&str> = blacklist.entries.iter().map(String::as_str).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            ["host.io", "missed.com", "||ads.org^", "||tracker.net^"]
+        );
+    }
+
+    #[test]
+    fn apply_adds_to_the_given_categories_only() {
+        let blacklist = BlacklistManager::parse("missed.com\n");
+        let mut category_domains: HashMap<String, HashSet<String>> = HashMap::from([
+            ("ads".to_string(), HashSet::from(["missed.com".to_string()])),
+            ("tracking".to_string(), HashSet::new()),
+        ]);
+
+        let added = blacklist.apply(
+            &mut category_domains,
+            &["ads".to_string(), "malicious".to_string()],
+        );
+        assert_eq!(added, 1);
+        assert!(category_domains["malicious"].contains("missed.com"));
+        assert!(category_domains["tracking"].is_empty());
+    }
+
+    #[test]
+    fn whitelist_wins_over_blacklist() {
+        let blacklist = BlacklistManager::parse("forced.com\nallowed.com\ncdn.allowed.org\n");
+        let whitelist = WhitelistManager::parse("allowed.com\nallowed.org\n", true);
+        let mut category_domains = HashMap::new();
+        blacklist.apply(&mut category_domains, &["ads".to_string()]);
+
+        let (kept, removed) = whitelist.filter_domains(&category_domains["ads"]);
+        assert_eq!(removed.total(), 2);
+        assert_eq!(kept, HashSet::from(["forced.com".to_string()]));
+    }
+}
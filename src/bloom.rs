@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"PHBLOOM1";
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+/// Offset for the second hash; any value other than `FNV_OFFSET` works.
+const FNV_OFFSET_2: u64 = 0x84222325_cbf29ce4;
+
+/// 64-bit FNV-1a. Used instead of `std`'s hasher because the exported filter
+/// must hash identically in every build that reads it.
+fn fnv1a(data: &[u8], offset: u64) -> u64 {
+    data.iter()
+        .fold(offset, |h, &b| (h ^ u64::from(b)).wrapping_mul(FNV_PRIME))
+}
+
+/// Parse a `--bloom-fp-rate` value: a false-positive rate between 0 and 1.
+pub fn parse_fp_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate < 1.0 => Ok(rate),
+        _ => Err(format!(
+            "expected a false-positive rate between 0 and 1, got '{s}'"
+        )),
+    }
+}
+
+/// Bloom filter over domain keys, using double hashing (`h1 + i * h2`) to
+/// derive each of the `num_hashes` bit positions.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_bits: u64,
+    num_hashes: u32,
+    items: u64,
+}
+
+impl BloomFilter {
+    /// Size the filter for `expected_items` at the given false-positive rate.
+    pub fn with_rate(expected_items: usize, fp_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-n * fp_rate.ln() / (ln2 * ln2)).ceil().max(8.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().clamp(1.0, 32.0) as u32;
+
+        Self {
+            bits: vec![0; num_bits.div_ceil(8) as usize],
+            num_bits,
+            num_hashes,
+            items: 0,
+        }
+    }
+
+    fn positions(&self, key: &str) -> impl Iterator<Item = u64> {
+        let h1 = fnv1a(key.as_bytes(), FNV_OFFSET);
+        let h2 = fnv1a(key.as_bytes(), FNV_OFFSET_2) | 1;
+        let num_bits = self.num_bits;
+        (0..u64::from(self.num_hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for pos in self.positions(key) {
+            self.bits[(pos / 8) as usize] |= 1 << (pos % 8);
+        }
+        self.items += 1;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|pos| self.bits[(pos / 8) as usize] & (1 << (pos % 8)) != 0)
+    }
+
+    /// Write the filter as: magic `PHBLOOM1`, bit count (u64 LE), hash count
+    /// (u32 LE), item count (u64 LE), then the bit array.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut w = std::io::BufWriter::new(file);
+        w.write_all(MAGIC)?;
+        w.write_all(&self.num_bits.to_le_bytes())?;
+        w.write_all(&self.num_hashes.to_le_bytes())?;
+        w.write_all(&self.items.to_le_bytes())?;
+        w.write_all(&self.bits)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fp_rate_requires_a_rate_between_0_and_1() {
+        assert_eq!(parse_fp_rate("0.001"), Ok(0.001));
+        assert!(parse_fp_rate("0").is_err());
+        assert!(parse_fp_rate("1").is_err());
+        assert!(parse_fp_rate("-0.5").is_err());
+        assert!(parse_fp_rate("NaN").is_err());
+        assert!(parse_fp_rate("low").is_err());
+    }
+
+    #[test]
+    fn inserted_keys_are_found() {
+        let mut bloom = BloomFilter::with_rate(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(&format!("ads{i}.example.com"));
+        }
+        assert!((0..1000).all(|i| bloom.contains(&format!("ads{i}.example.com"))));
+    }
+
+    #[test]
+    fn false_positive_rate_is_near_target() {
+        let mut bloom = BloomFilter::with_rate(10_000, 0.01);
+        for i in 0..10_000 {
+            bloom.insert(&format!("ads{i}.example.com"));
+        }
+        let false_positives = (0..10_000)
+            .filter(|i| bloom.contains(&format!("clean{i}.example.org")))
+            .count();
+        assert!(false_positives < 200, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_vectors() {
+        assert_eq!(fnv1a(b"", FNV_OFFSET), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a", FNV_OFFSET), 0xaf63_dc4c_8601_ec8c);
+    }
+}
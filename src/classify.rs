@@ -0,0 +1,85 @@
+/// Categories `--auto-categorize` can assign, in the order they are tried.
+pub const CATEGORIES: &[&str] = &["malicious", "tracking", "advertising"];
+
+/// Word prefixes that suggest a category. A domain is split into alphabetic
+/// tokens (`ad-server2.example.com` -> `ad`, `server`, `example`, `com`) and
+/// matches when any token starts with one of these.
+const MALICIOUS: &[&str] = &[
+    "malware", "phish", "virus", "trojan", "ransom", "botnet", "exploit", "scam", "spyware",
+];
+const TRACKING: &[&str] = &[
+    "track",
+    "analytic",
+    "telemetry",
+    "metric",
+    "stats",
+    "pixel",
+    "beacon",
+    "collect",
+    "insight",
+];
+const ADVERTISING: &[&str] = &[
+    "ads",
+    "adserv",
+    "adsystem",
+    "advert",
+    "adnxs",
+    "doubleclick",
+    "banner",
+    "popunder",
+];
+/// Tokens that are ads on their own but too common as a prefix (`admin`, `adobe`).
+const ADVERTISING_EXACT: &[&str] = &["ad"];
+
+/// Guess a category for `domain` from keywords in its name. This is a
+/// heuristic: it misses domains with neutral names and can misfire on
+/// unrelated words that share a prefix.
+pub fn classify(domain: &str) -> Option<&'static str> {
+    let tokens: Vec<&str> = domain
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|t| !t.is_empty())
+        .collect();
+    let has_prefix = |words: &[&str]| {
+        tokens
+            .iter()
+            .any(|t| words.iter().any(|w| t.starts_with(w)))
+    };
+
+    if has_prefix(MALICIOUS) {
+        Some("malicious")
+    } else if has_prefix(TRACKING) {
+        Some("tracking")
+    } else if has_prefix(ADVERTISING) || tokens.iter().any(|t| ADVERTISING_EXACT.contains(t)) {
+        Some("advertising")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_by_keyword() {
+        assert_eq!(classify("ads.example.com"), Some("advertising"));
+        assert_eq!(classify("ad.example.com"), Some("advertising"));
+        assert_eq!(classify("adserver2.example.net"), Some("advertising"));
+        assert_eq!(classify("google-analytics.com"), Some("tracking"));
+        assert_eq!(classify("telemetry.vendor.io"), Some("tracking"));
+        assert_eq!(classify("login-phishing.example.org"), Some("malicious"));
+    }
+
+    #[test]
+    fn malicious_wins_over_other_keywords() {
+        assert_eq!(classify("ads-malware.example.com"), Some("malicious"));
+        assert_eq!(classify("track.ads.example.com"), Some("tracking"));
+    }
+
+    #[test]
+    fn neutral_and_lookalike_names_are_unclassified() {
+        assert_eq!(classify("example.com"), None);
+        assert_eq!(classify("admin.example.com"), None);
+        assert_eq!(classify("downloads.example.com"), None);
+    }
+}
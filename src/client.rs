@@ -1,49 +1,434 @@
 use anyhow::{anyhow, Result};
 use log::debug;
 use reqwest::header;
-use reqwest::Client;
 use reqwest::StatusCode;
-use std::time::Duration;
+use reqwest::{Client, Response};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use url::Url;
 
-const MAX_RETRIES: u32 = 3;
-const RETRY_BACKOFF_MS: u64 = 500;
+/// Default `--max-retries`.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+/// Default `--retry-backoff-ms`, doubled after each retry.
+pub const DEFAULT_RETRY_BACKOFF_MS: u64 = 500;
 const RETRY_STATUS_CODES: &[u16] = &[429, 500, 502, 503, 504];
+/// Longest `Retry-After` honoured; a server asking for more is retried after this.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
 const USER_AGENT: &str = "Pi-hole Blocklist Optimizer/3.0";
 
+/// Bodies at least this large, or of unknown length, are streamed to the
+/// spool file when the caller gives one instead of being buffered in memory.
+pub const STREAM_MIN_BYTES: u64 = 8 << 20;
+
 #[derive(Clone)]
 pub struct HttpClient {
     client: Client,
+    /// `--rate-limit`, shared by every clone so all downloads draw on it.
+    rate_limiter: Option<Arc<HostRateLimiter>>,
+    max_retries: u32,
+    retry_backoff_ms: u64,
+}
+
+/// Parse a `--rate-limit` value: requests per second, greater than zero.
+pub fn parse_rate_limit(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rps) if rps.is_finite() && rps > 0.0 => Ok(rps),
+        _ => Err(format!(
+            "expected requests per second greater than 0, got '{s}'"
+        )),
+    }
+}
+
+/// Token bucket per URL host: each host gets `rate` requests per second with
+/// a burst of one, so requests to one host are spaced out while other hosts
+/// proceed in parallel.
+struct HostRateLimiter {
+    rate: f64,
+    /// Host -> (tokens, last refill). Tokens go negative when requests queue
+    /// up; each waits for the debt ahead of it to be repaid.
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl HostRateLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for `host` as of `now`, returning how long to wait
+    /// before the request may be sent.
+    fn reserve(&self, host: &str, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, last) = buckets.entry(host.to_string()).or_insert((1.0, now));
+        let refilled = now.saturating_duration_since(*last).as_secs_f64() * self.rate;
+        *tokens = (*tokens + refilled).min(1.0) - 1.0;
+        *last = now;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+
+    async fn wait(&self, url: &str) {
+        let Some(host) = url_host(url) else {
+            return;
+        };
+        let delay = self.reserve(&host, Instant::now());
+        if !delay.is_zero() {
+            debug!("Rate limiting {host}: waiting {}ms", delay.as_millis());
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// The host of `url`. Logged in place of the full URL, whose path or query
+/// may hold an expanded secret.
+fn url_host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(String::from)
 }
 
 pub struct DownloadResult {
-    pub content: Option<Vec<u8>>,
+    pub content: Option<Body>,
     pub etag: Option<String>,
     pub last_modified: Option<String>,
+    pub content_type: Option<String>,
     pub was_modified: bool,
 }
 
+/// A downloaded list body.
+pub enum Body {
+    Memory(Vec<u8>),
+    /// Written to disk chunk by chunk as it arrived.
+    Spooled(SpooledBody),
+}
+
+impl Body {
+    /// The whole body in memory, reading a spooled body back from disk.
+    pub fn into_bytes(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Body::Memory(content) => Ok(content),
+            Body::Spooled(spooled) => std::fs::read(spooled.path()),
+        }
+    }
+}
+
+/// A body streamed to a file. The file is deleted when this is dropped
+/// unless `persist` has moved it.
+pub struct SpooledBody {
+    path: PathBuf,
+    len: u64,
+}
+
+impl SpooledBody {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Move the file to `to`, keeping it.
+    pub fn persist(self, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, to)
+    }
+}
+
+impl Drop for SpooledBody {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Write `response`'s body to `path` as chunks arrive.
+async fn spool_response(mut response: Response, path: &Path) -> Result<SpooledBody> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut body = SpooledBody {
+        path: path.to_path_buf(),
+        len: 0,
+    };
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(reqwest::Error::without_url)?
+    {
+        file.write_all(&chunk).await?;
+        body.len += chunk.len() as u64;
+    }
+    file.flush().await?;
+    debug!("Spooled {} bytes to {}", body.len, path.display());
+    Ok(body)
+}
+
+/// How to request a list: GET or POST, plus any extra headers (e.g. API keys).
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct RequestSpec {
+    pub post: bool,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestSpec {
+    /// Whether `name: value` can be sent as a request header, checked when
+    /// the config is loaded so a bad header never reaches the retry loop.
+    pub fn is_valid_header(name: &str, value: &str) -> bool {
+        header::HeaderName::from_bytes(name.as_bytes()).is_ok()
+            && header::HeaderValue::from_str(value).is_ok()
+    }
+}
+
+// Header values are often credentials, so only their names are ever printed.
+impl fmt::Debug for RequestSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestSpec")
+            .field("post", &self.post)
+            .field(
+                "headers",
+                &self
+                    .headers
+                    .iter()
+                    .map(|(name, _)| format!("{name}: <redacted>"))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// A failed download, with enough detail to report it as structured data.
+#[derive(Debug)]
+pub struct DownloadError {
+    pub status: Option<u16>,
+    pub retries: u32,
+    pub message: String,
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// Markers of a Cloudflare browser-check page ("Just a moment...").
+const CHALLENGE_BODY_MARKERS: &[&str] = &[
+    "challenge-platform",
+    "cf-chl",
+    "cf_chl_opt",
+    "jschl",
+    "<title>Just a moment...</title>",
+];
+
+/// Whether an error response is a Cloudflare JS challenge rather than a plain
+/// HTTP error. Cloudflare marks challenges with `cf-mitigated: challenge`; older
+/// "I'm under attack" pages only show it in the body of a `server: cloudflare` reply.
+fn is_cloudflare_challenge(headers: &header::HeaderMap, body: &[u8]) -> bool {
+    if headers
+        .get("cf-mitigated")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"challenge"))
+    {
+        return true;
+    }
+    if !served_by_cloudflare(headers) {
+        return false;
+    }
+    let body = String::from_utf8_lossy(body);
+    CHALLENGE_BODY_MARKERS.iter().any(|m| body.contains(m))
+}
+
+fn served_by_cloudflare(headers: &header::HeaderMap) -> bool {
+    headers.contains_key("cf-ray")
+        || headers
+            .get(header::SERVER)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.to_ascii_lowercase().contains("cloudflare"))
+}
+
+/// The delay a `Retry-After` header asks for, given as delta-seconds or an
+/// HTTP-date, capped at `MAX_RETRY_AFTER`. A date already past means now.
+fn retry_after(
+    headers: &header::HeaderMap,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (date.with_timezone(&chrono::Utc) - now)
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// Consume an error response and check it for a Cloudflare challenge. The body
+/// is only read when the response came from Cloudflare.
+async fn is_challenge_response(response: reqwest::Response) -> bool {
+    let headers = response.headers().clone();
+    if !headers.contains_key("cf-mitigated") && !served_by_cloudflare(&headers) {
+        return false;
+    }
+    let body = response.bytes().await.unwrap_or_default();
+    is_cloudflare_challenge(&headers, &body)
+}
+
+/// Compare a received body length against the `Content-Length` header, if any.
+fn check_length(expected: Option<u64>, received: u64) -> Result<(), String> {
+    match expected {
+        Some(expected) if expected != received => Err(format!(
+            "Truncated response: received {received} of {expected} bytes"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The file a `file://` URL or bare path (`/lists/a.txt`, `./a.txt`,
+/// `../a.txt`) source names, or `None` for network URLs.
+pub fn local_source_path(url: &str) -> Option<PathBuf> {
+    if url.starts_with("file:") {
+        return Url::parse(url).ok()?.to_file_path().ok();
+    }
+    ["/", "./", "../"]
+        .iter()
+        .any(|prefix| url.starts_with(prefix))
+        .then(|| PathBuf::from(url))
+}
+
+/// Read a local list source. Its modification time stands in for
+/// Last-Modified, so an unchanged file is skipped like a 304 response.
+fn read_local(path: &Path, last_modified: Option<&str>) -> Result<DownloadResult, DownloadError> {
+    let failure = |e: std::io::Error| DownloadError {
+        status: None,
+        retries: 0,
+        message: format!("Failed to read {}: {e}", path.display()),
+    };
+    let mtime = std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map_err(failure)?;
+    let mtime = chrono::DateTime::<chrono::Utc>::from(mtime).to_rfc3339();
+
+    if last_modified == Some(mtime.as_str()) {
+        return Ok(DownloadResult {
+            content: None,
+            etag: None,
+            last_modified: Some(mtime),
+            content_type: None,
+            was_modified: false,
+        });
+    }
+    let content = std::fs::read(path).map_err(failure)?;
+    debug!("Read {} bytes from {}", content.len(), path.display());
+    Ok(DownloadResult {
+        content: Some(Body::Memory(content)),
+        etag: None,
+        last_modified: Some(mtime),
+        content_type: None,
+        was_modified: true,
+    })
+}
+
 impl HttpClient {
-    pub fn new(timeout_secs: u64) -> Result<Self> {
-        let client = Client::builder()
+    /// `timeout_secs` bounds the whole request, body included; `connect_secs`
+    /// additionally bounds establishing the connection so unreachable hosts
+    /// fail fast without cutting short slow transfers.
+    pub fn new(timeout_secs: u64, connect_secs: Option<u64>) -> Result<Self> {
+        let mut builder = Client::builder()
             .timeout(Duration::from_secs(timeout_secs))
             .user_agent(USER_AGENT)
             .gzip(true)
-            .brotli(true)
-            .build()?;
+            .brotli(true);
+        if let Some(secs) = connect_secs {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+        let client = builder.build()?;
+
+        Ok(Self {
+            client,
+            rate_limiter: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff_ms: DEFAULT_RETRY_BACKOFF_MS,
+        })
+    }
 
-        Ok(Self { client })
+    /// Retry failed requests up to `max_retries` times, waiting
+    /// `backoff_ms` before the first retry and doubling it each time after.
+    pub fn with_retries(mut self, max_retries: u32, backoff_ms: u64) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff_ms = backoff_ms;
+        self
     }
 
+    /// Exponential backoff before retry number `attempt` (1-based).
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX);
+        Duration::from_millis(self.retry_backoff_ms.saturating_mul(factor))
+    }
+
+    /// Throttle requests to `rps` per second per host (`--rate-limit`).
+    /// Retries draw on the same budget; 429 backoff still applies on top.
+    pub fn with_rate_limit(mut self, rps: Option<f64>) -> Self {
+        self.rate_limiter = rps.map(|rate| Arc::new(HostRateLimiter::new(rate)));
+        self
+    }
+
+    pub async fn post_json<T: Serialize>(&self, url: &str, body: &T) -> Result<()> {
+        let response = self
+            .client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(body)?)
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("HTTP {status}"));
+        }
+        Ok(())
+    }
+
+    /// Download `url`. With `spool`, large bodies and those of unknown length
+    /// are streamed to that file rather than held in memory; 304s and small
+    /// bodies never touch it.
     pub async fn download(
         &self,
         url: &str,
+        spec: &RequestSpec,
         etag: Option<&str>,
         last_modified: Option<&str>,
-    ) -> Result<DownloadResult> {
+        spool: Option<&Path>,
+    ) -> Result<DownloadResult, DownloadError> {
+        if let Some(path) = local_source_path(url) {
+            return read_local(&path, last_modified);
+        }
+
+        // Messages name only the host; callers know which list this is
+        let host = url_host(url).unwrap_or_default();
         let mut attempts = 0u32;
 
         loop {
-            let mut request = self.client.get(url);
+            if let Some(limiter) = &self.rate_limiter {
+                limiter.wait(url).await;
+            }
+
+            let mut request = if spec.post {
+                self.client.post(url)
+            } else {
+                self.client.get(url)
+            };
+            for (name, value) in &spec.headers {
+                request = request.header(name, value);
+            }
 
             if let Some(etag) = etag {
                 request = request.header(header::IF_NONE_MATCH, etag);
@@ -52,7 +437,7 @@ impl HttpClient {
                 request = request.header(header::IF_MODIFIED_SINCE, lm);
             }
 
-            match request.send().await {
+            match request.send().await.map_err(reqwest::Error::without_url) {
                 Ok(response) => {
                     let status = response.status();
 
@@ -61,22 +446,47 @@ impl HttpClient {
                             content: None,
                             etag: etag.map(String::from),
                             last_modified: last_modified.map(String::from),
+                            content_type: None,
                             was_modified: false,
                         });
                     }
 
-                    if RETRY_STATUS_CODES.contains(&status.as_u16()) && attempts < MAX_RETRIES {
-                        attempts += 1;
-                        let delay = RETRY_BACKOFF_MS * 2u64.pow(attempts - 1);
-                        debug!(
-                            "Retry {attempts}/{MAX_RETRIES} for {url} (HTTP {status}), waiting {delay}ms"
-                        );
-                        tokio::time::sleep(Duration::from_millis(delay)).await;
-                        continue;
-                    }
-
                     if !status.is_success() {
-                        return Err(anyhow!("HTTP {status} for {url}"));
+                        let requested_delay = retry_after(response.headers(), chrono::Utc::now());
+
+                        // Retrying can't pass a browser challenge, so fail straight away
+                        if is_challenge_response(response).await {
+                            return Err(DownloadError {
+                                status: Some(status.as_u16()),
+                                retries: attempts,
+                                message: format!(
+                                    "Cloudflare JS challenge (HTTP {status}) from {host}; \
+                                     use a mirror, or pass a cf_clearance cookie with \
+                                     header=Cookie: ..."
+                                ),
+                            });
+                        }
+
+                        if RETRY_STATUS_CODES.contains(&status.as_u16())
+                            && attempts < self.max_retries
+                        {
+                            attempts += 1;
+                            // The server's Retry-After wins over our own backoff
+                            let delay = requested_delay.unwrap_or_else(|| self.backoff(attempts));
+                            debug!(
+                                "Retry {attempts}/{} for {host} (HTTP {status}), waiting {}ms",
+                                self.max_retries,
+                                delay.as_millis()
+                            );
+                            tokio::time::sleep(delay).await;
+                            continue;
+                        }
+
+                        return Err(DownloadError {
+                            status: Some(status.as_u16()),
+                            retries: attempts,
+                            message: format!("HTTP {status} from {host}"),
+                        });
                     }
 
                     let new_etag = response
@@ -89,27 +499,322 @@ impl HttpClient {
                         .get(header::LAST_MODIFIED)
                         .and_then(|v| v.to_str().ok())
                         .map(String::from);
+                    let content_type = response
+                        .headers()
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .map(String::from);
 
-                    let content = response.bytes().await?.to_vec();
+                    // A connection reset mid-transfer can leave a short body that
+                    // still parses, so treat it like any other retryable failure.
+                    let expected = response.content_length();
+                    let body = match spool {
+                        Some(path) if expected.map_or(true, |len| len >= STREAM_MIN_BYTES) => {
+                            spool_response(response, path)
+                                .await
+                                .map(|body| (body.len(), Body::Spooled(body)))
+                                .map_err(|e| format!("{e:#}"))
+                        }
+                        _ => response
+                            .bytes()
+                            .await
+                            .map(|body| (body.len() as u64, Body::Memory(body.to_vec())))
+                            .map_err(|e| e.without_url().to_string()),
+                    };
+                    let failure = match body {
+                        Ok((len, body)) => match check_length(expected, len) {
+                            Ok(()) => {
+                                return Ok(DownloadResult {
+                                    content: Some(body),
+                                    etag: new_etag,
+                                    last_modified: new_last_modified,
+                                    content_type,
+                                    was_modified: true,
+                                });
+                            }
+                            Err(message) => message,
+                        },
+                        Err(message) => message,
+                    };
 
-                    return Ok(DownloadResult {
-                        content: Some(content),
-                        etag: new_etag,
-                        last_modified: new_last_modified,
-                        was_modified: true,
+                    if attempts < self.max_retries {
+                        attempts += 1;
+                        let delay = self.backoff(attempts);
+                        debug!(
+                            "Retry {attempts}/{} for {host} ({failure}), waiting {}ms",
+                            self.max_retries,
+                            delay.as_millis()
+                        );
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    return Err(DownloadError {
+                        status: Some(status.as_u16()),
+                        retries: attempts,
+                        message: failure,
+                    });
+                }
+                // The request itself could not be built; retrying won't change that
+                Err(e) if e.is_builder() => {
+                    return Err(DownloadError {
+                        status: None,
+                        retries: attempts,
+                        message: e.to_string(),
                     });
                 }
                 Err(e) => {
-                    if attempts < MAX_RETRIES {
+                    if attempts < self.max_retries {
                         attempts += 1;
-                        let delay = RETRY_BACKOFF_MS * 2u64.pow(attempts - 1);
-                        debug!("Retry {attempts}/{MAX_RETRIES} for {url} ({e}), waiting {delay}ms");
-                        tokio::time::sleep(Duration::from_millis(delay)).await;
+                        let delay = self.backoff(attempts);
+                        debug!(
+                            "Retry {attempts}/{} for {host} ({e}), waiting {}ms",
+                            self.max_retries,
+                            delay.as_millis()
+                        );
+                        tokio::time::sleep(delay).await;
                     } else {
-                        return Err(e.into());
+                        return Err(DownloadError {
+                            status: e.status().map(|s| s.as_u16()),
+                            retries: attempts,
+                            message: e.to_string(),
+                        });
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn detects_cloudflare_challenges() {
+        let mut mitigated = header::HeaderMap::new();
+        mitigated.insert("cf-mitigated", "challenge".parse().unwrap());
+        assert!(is_cloudflare_challenge(&mitigated, b""));
+
+        let mut cloudflare = header::HeaderMap::new();
+        cloudflare.insert(header::SERVER, "cloudflare".parse().unwrap());
+        let page = b"<html><head><title>Just a moment...</title></head>\
+            <script src=\"/cdn-cgi/challenge-platform/h/b/orchestrate/jsch/v1\"></script>";
+        assert!(is_cloudflare_challenge(&cloudflare, page));
+
+        // A plain Cloudflare 403 and a challenge-looking body elsewhere don't count
+        assert!(!is_cloudflare_challenge(&cloudflare, b"Forbidden"));
+        let mut nginx = header::HeaderMap::new();
+        nginx.insert(header::SERVER, "nginx".parse().unwrap());
+        assert!(!is_cloudflare_challenge(&nginx, page));
+    }
+
+    #[test]
+    fn rate_limiter_spaces_requests_per_host() {
+        let limiter = HostRateLimiter::new(2.0);
+        let start = Instant::now();
+
+        // The first request to a host goes straight out; the next ones queue
+        // half a second apart, while another host is unaffected.
+        assert_eq!(limiter.reserve("a.com", start), Duration::ZERO);
+        assert_eq!(limiter.reserve("a.com", start), Duration::from_millis(500));
+        assert_eq!(limiter.reserve("a.com", start), Duration::from_millis(1000));
+        assert_eq!(limiter.reserve("b.com", start), Duration::ZERO);
+
+        // Once the debt is repaid and a token has accrued, no wait; idle time
+        // never banks more than one token.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve("a.com", later), Duration::ZERO);
+        assert_eq!(limiter.reserve("a.com", later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn parse_rate_limit_requires_positive_rate() {
+        assert_eq!(parse_rate_limit("0.5"), Ok(0.5));
+        assert!(parse_rate_limit("0").is_err());
+        assert!(parse_rate_limit("-1").is_err());
+        assert!(parse_rate_limit("inf").is_err());
+        assert!(parse_rate_limit("fast").is_err());
+    }
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let headers = |value: &str| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(
+            retry_after(&headers("2"), now),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            retry_after(&headers("Wed, 01 May 2024 12:00:30 GMT"), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(&headers("Wed, 01 May 2024 11:59:00 GMT"), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("86400"), now), Some(MAX_RETRY_AFTER));
+        assert_eq!(retry_after(&headers("soon"), now), None);
+        assert_eq!(retry_after(&header::HeaderMap::new(), now), None);
+    }
+
+    #[tokio::test]
+    async fn waits_for_retry_after_before_retrying() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.txt", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let responses = [
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 2\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 14\r\nConnection: close\r\n\r\n0.0.0.0 a.com\n",
+            ];
+            let mut arrivals = Vec::new();
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                arrivals.push(Instant::now());
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            arrivals
+        });
+
+        let client = HttpClient::new(10, None).unwrap();
+        let result = client
+            .download(&url, &RequestSpec::default(), None, None, None)
+            .await;
+        let Ok(result) = result else {
+            panic!("download should succeed after the retry");
+        };
+        assert_eq!(
+            result.content.unwrap().into_bytes().unwrap(),
+            b"0.0.0.0 a.com\n"
+        );
+
+        let arrivals = server.await.unwrap();
+        assert!(arrivals[1] - arrivals[0] >= Duration::from_millis(1900));
+    }
+
+    #[test]
+    fn backoff_doubles_without_overflowing() {
+        let client = HttpClient::new(10, None).unwrap().with_retries(100, 200);
+        assert_eq!(client.backoff(1), Duration::from_millis(200));
+        assert_eq!(client.backoff(3), Duration::from_millis(800));
+        assert_eq!(client.backoff(100), Duration::from_millis(u64::MAX));
+    }
+
+    #[test]
+    fn check_length_detects_truncation() {
+        assert!(check_length(Some(100), 100).is_ok());
+        assert!(check_length(None, 42).is_ok());
+        let err = check_length(Some(100), 60).unwrap_err();
+        assert!(err.contains("received 60 of 100 bytes"));
+    }
+
+    #[test]
+    fn local_sources_are_read_from_disk_and_skipped_when_unchanged() {
+        assert_eq!(
+            local_source_path("file:///srv/lists/a.txt"),
+            Some(PathBuf::from("/srv/lists/a.txt"))
+        );
+        assert_eq!(
+            local_source_path("./custom.txt"),
+            Some(PathBuf::from("./custom.txt"))
+        );
+        assert_eq!(local_source_path("https://a.com/l.txt"), None);
+        assert_eq!(local_source_path("custom.txt"), None);
+
+        let dir = TempDir::new("local");
+        let path = dir.join("local.txt");
+        std::fs::write(&path, "0.0.0.0 ads.example.com\n").unwrap();
+        let first = read_local(&path, None).unwrap();
+        assert!(first.was_modified);
+        let last_modified = first.last_modified.clone();
+        assert_eq!(
+            first.content.unwrap().into_bytes().unwrap(),
+            b"0.0.0.0 ads.example.com\n"
+        );
+
+        let again = read_local(&path, last_modified.as_deref()).unwrap();
+        assert!(!again.was_modified);
+        assert!(again.content.is_none());
+
+        std::fs::remove_file(&path).unwrap();
+        let Err(err) = read_local(&path, None) else {
+            panic!("a missing file must fail");
+        };
+        assert_eq!(err.status, None);
+    }
+
+    #[test]
+    fn spooled_bodies_are_removed_unless_persisted() {
+        let dir = TempDir::new("spool");
+        let spooled = |name: &str| {
+            let path = dir.join(format!("{name}.part"));
+            std::fs::write(&path, "0.0.0.0 ads.example.com\n").unwrap();
+            SpooledBody { path, len: 24 }
+        };
+
+        let dropped = spooled("dropped");
+        let dropped_path = dropped.path().to_path_buf();
+        drop(dropped);
+        assert!(!dropped_path.exists());
+
+        let kept = spooled("kept");
+        let target = dir.join("kept.raw");
+        kept.persist(&target).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&target).unwrap(),
+            "0.0.0.0 ads.example.com\n"
+        );
+
+        let read = Body::Spooled(spooled("read"));
+        assert_eq!(read.into_bytes().unwrap().len(), 24);
+    }
+
+    #[test]
+    fn validates_header_names_and_values() {
+        assert!(RequestSpec::is_valid_header("X-Api-Key", "abc:123"));
+        assert!(!RequestSpec::is_valid_header("X Api Key", "abc"));
+        assert!(!RequestSpec::is_valid_header("X-Api-Key", "a\nb"));
+    }
+
+    #[tokio::test]
+    async fn request_build_errors_are_not_retried() {
+        let client = HttpClient::new(10, None).unwrap().with_retries(3, 60_000);
+        let spec = RequestSpec {
+            post: false,
+            headers: vec![("X Api Key".to_string(), "abc".to_string())],
+        };
+        let Err(err) = client
+            .download("http://127.0.0.1:9/list.txt", &spec, None, None, None)
+            .await
+        else {
+            panic!("an invalid header must fail");
+        };
+        assert_eq!((err.status, err.retries), (None, 0));
+    }
+
+    #[test]
+    fn request_spec_debug_redacts_header_values() {
+        let spec = RequestSpec {
+            post: true,
+            headers: vec![("X-Api-Key".to_string(), "s3cret".to_string())],
+        };
+        let printed = format!("{spec:?}");
+        assert!(printed.contains("X-Api-Key"));
+        assert!(!printed.contains("s3cret"));
+    }
+}
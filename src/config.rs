@@ -1,35 +1,132 @@
 use anyhow::{bail, Context, Result};
-use log::{info, warn};
-use std::collections::HashSet;
-use std::path::Path;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use url::Url;
 
+use crate::client::{local_source_path, RequestSpec};
+use crate::output::{LineEnding, OutputFormat};
 use crate::progress::ProgressTracker;
 
+/// Order categories are processed and reported in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CategoryOrder {
+    /// First appearance in the configuration file.
+    #[default]
+    Config,
+    /// Alphabetical by name.
+    Alpha,
+}
+
 pub struct AppConfig {
     pub config_file: String,
     pub whitelist_file: String,
+    pub blacklist_file: Option<String>,
+    pub blacklist_category: Option<String>,
     pub base_dir: String,
     pub prod_dir: String,
     pub threads: usize,
     pub timeout: u64,
+    /// Connection-establishment timeout in seconds; `None` leaves only `timeout`.
+    pub connect_timeout: Option<u64>,
     pub skip_download: bool,
     pub skip_optimize: bool,
+    /// Write no per-list files under `base_dir`; lists only feed the production set.
+    pub no_individual_files: bool,
+    pub refresh_one: Option<String>,
+    pub count_only: bool,
+    /// Reject a download yielding fewer domains than this (0 = no floor).
+    pub min_domains: usize,
+    pub progress_sync_each: bool,
+    pub shuffle: bool,
+    pub shuffle_seed: Option<u64>,
     pub incremental: bool,
+    /// Lists downloaded more recently than this are reused without a request.
+    pub min_refresh_interval: Option<Duration>,
     pub dry_run: bool,
     pub quiet: bool,
     pub verbose: bool,
     pub whitelist_subdomain: bool,
+    pub require_whitelist: bool,
     pub whitelist_report: bool,
+    pub whitelist_warn_threshold: usize,
     pub abp_lists: Vec<String>,
+    pub write_empty_lists: bool,
+    pub line_ending: LineEnding,
+    /// Syntax of the production lists under `prod_dir`.
+    pub output_format: OutputFormat,
+    pub category_order: CategoryOrder,
+    pub webhook_url: Option<String>,
+    pub webhook_threshold: usize,
+    pub annotate_sources: bool,
+    pub backup_master: bool,
+    pub per_list_stats: bool,
+    pub top_lists: usize,
+    pub count_diff: bool,
+    pub collapse_subdomains: bool,
+    pub rate_limit: Option<f64>,
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+    pub summary_lists: bool,
+    pub sinkhole_ips_only: bool,
+    pub sinkhole_ips: Vec<String>,
+    /// Addresses written hosts lines point at, round-robin; empty means 0.0.0.0.
+    pub hosts_ips: Vec<IpAddr>,
+    pub drop_source_wildcards: bool,
+    pub keep_dnstype_rules: bool,
+    pub auto_categorize: bool,
+    pub salvage_host_urls: bool,
+    pub overlap_report: bool,
+    pub overlap_file: Option<String>,
+    pub export_bloom: Option<String>,
+    pub export_allowlist: Option<String>,
+    pub whitelist_audit_json: Option<String>,
+    pub combined_annotated: Option<String>,
+    pub bloom_fp_rate: f64,
+    pub subtract: Option<String>,
+    pub report_homographs: bool,
+    pub json_errors: Option<String>,
+    pub stats_json: Option<String>,
+    pub allow_empty: bool,
+    pub strict_config: bool,
+    /// Category -> directory replacing both `base_dir` and `prod_dir` for that category.
+    pub category_dirs: HashMap<String, String>,
+}
+
+impl AppConfig {
+    /// Directory holding the individual lists of `category`.
+    pub fn list_dir(&self, category: &str) -> PathBuf {
+        let base = self.category_dirs.get(category).unwrap_or(&self.base_dir);
+        Path::new(base).join(category)
+    }
+
+    /// Directory holding the production list(s) of `category`.
+    pub fn category_prod_dir(&self, category: &str) -> &Path {
+        Path::new(self.category_dirs.get(category).unwrap_or(&self.prod_dir))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Blocklist {
     pub url: String,
+    /// `url` as written in the config, before `${VAR}` expansion, for logs and
+    /// reports so expanded secrets are never printed.
+    pub display_url: String,
     pub name: String,
     pub category: String,
+    /// `false` for a `!url` line (or `enabled = false`): listed, but not downloaded.
+    pub enabled: bool,
     pub allow_wildcards: bool,
+    /// `auto`: sort domains into categories by keyword with `--auto-categorize`.
+    pub auto_categorize: bool,
+    pub request: RequestSpec,
+    /// Expected SHA-256 of the downloaded content (lowercase hex), if pinned.
+    pub sha256: Option<String>,
+    /// `format=json`: dotted path to the domain array (empty = auto).
+    pub json_path: Option<String>,
     pub etag: Option<String>,
     pub last_modified: Option<String>,
 }
@@ -39,94 +136,976 @@ pub struct ParsedSource {
     pub url: String,
     pub name: String,
     pub category: String,
+    pub enabled: bool,
     pub allow_wildcards: bool,
+    pub auto_categorize: bool,
+    pub request: RequestSpec,
+    pub sha256: Option<String>,
+    pub json_path: Option<String>,
+    /// Flags that were not recognised; the line still loads without them.
+    pub unknown_flags: Vec<String>,
+}
+
+/// Remove a trailing `# comment`. Only a `#` preceded by whitespace starts a
+/// comment, so URL fragments like `list.txt#v2` are left intact.
+fn strip_inline_comment(line: &str) -> &str {
+    line.char_indices()
+        .find(|&(i, c)| c == '#' && line[..i].ends_with(char::is_whitespace))
+        .map_or(line, |(i, _)| &line[..i])
+}
+
+/// Split a config line on `|`. A backslash escapes the next `|` or `\`, so a URL
+/// containing a pipe can be written as `https://host/a\|b.txt`.
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('|' | '\\')) => {
+                fields.last_mut().unwrap().extend(chars.next());
+            }
+            '|' => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Why a config line was not accepted as a blocklist source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Blank or `#` comment line.
+    Blank,
+    /// Fewer than the three required `url|name|category` fields.
+    FieldCount(usize),
+    /// The first field is neither a URL nor a local path.
+    BadUrl(String),
+    /// A category that can't be used as a directory and file name.
+    BadCategory(String),
+    /// A malformed `header=`, `sha256=` or `format=` flag, or an unknown flag
+    /// under `--strict-config`.
+    BadFlag(String),
+    /// A header that can't be sent, by name; the value may be a secret.
+    BadHeader(String),
+    /// A `.toml` list with a missing, unknown or mistyped key.
+    Toml(String),
+    /// A `.toml` list key with an invalid value.
+    BadValue(String),
 }
 
-pub fn parse_source_line(line: &str) -> Option<ParsedSource> {
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::Blank => write!(f, "blank or comment line"),
+            SkipReason::FieldCount(n) => {
+                write!(f, "expected url|name|category, found {n} field(s)")
+            }
+            SkipReason::BadUrl(url) => write!(f, "not a URL or local path: {url}"),
+            SkipReason::BadCategory(category) => {
+                write!(f, "category is not a plain directory name: {category:?}")
+            }
+            SkipReason::BadFlag(flag) => write!(f, "invalid flag: {flag}"),
+            SkipReason::BadHeader(name) => write!(f, "invalid header: {name}"),
+            SkipReason::Toml(message) => f.write_str(message),
+            SkipReason::BadValue(key) => write!(f, "invalid value for {key}"),
+        }
+    }
+}
+
+pub fn parse_source_line(line: &str) -> Result<ParsedSource, SkipReason> {
     let line = line.trim();
     if line.is_empty() || line.starts_with('#') {
-        return None;
+        return Err(SkipReason::Blank);
     }
+    let line = strip_inline_comment(line).trim();
 
-    let parts: Vec<&str> = line.split('|').collect();
-    if parts.len() != 3 && parts.len() != 4 {
-        return None;
+    let parts = split_fields(line);
+    if parts.len() < 3 {
+        return Err(SkipReason::FieldCount(parts.len()));
     }
 
-    let url = parts[0].trim();
+    // A leading `!` disables the list without removing it
+    let (enabled, url) = match parts[0].trim().strip_prefix('!') {
+        Some(url) => (false, url.trim()),
+        None => (true, parts[0].trim()),
+    };
     let name = parts[1].trim();
     let category = parts[2].trim();
 
-    if Url::parse(url).is_err() {
-        return None;
+    if !is_source_url(url) {
+        return Err(SkipReason::BadUrl(url.to_string()));
+    }
+    if !is_safe_category(category) {
+        return Err(SkipReason::BadCategory(category.to_string()));
     }
 
-    let allow_wildcards = parts
-        .get(3)
-        .map(|f| f.trim().eq_ignore_ascii_case("abp"))
-        .unwrap_or(false);
+    // Every field after the category is a flag: `abp`, `auto`, `post`,
+    // `header=Name: value`, `sha256=<hex>` or `format=json[:path=a.b]`
+    let mut allow_wildcards = false;
+    let mut auto_categorize = false;
+    let mut request = RequestSpec::default();
+    let mut sha256 = None;
+    let mut json_path = None;
+    let mut unknown_flags = Vec::new();
+    for flag in &parts[3..] {
+        let flag = flag.trim();
+        let bad_flag = || SkipReason::BadFlag(flag.to_string());
+        if flag.eq_ignore_ascii_case("abp") {
+            allow_wildcards = true;
+        } else if flag.eq_ignore_ascii_case("auto") {
+            auto_categorize = true;
+        } else if flag.eq_ignore_ascii_case("post") {
+            request.post = true;
+        } else if let Some(header) = flag.strip_prefix("header=") {
+            let (header_name, value) = header.split_once(':').ok_or_else(bad_flag)?;
+            let (header_name, value) = (header_name.trim(), value.trim());
+            if header_name.is_empty() {
+                return Err(bad_flag());
+            }
+            if !RequestSpec::is_valid_header(header_name, value) {
+                return Err(SkipReason::BadHeader(header_name.to_string()));
+            }
+            request
+                .headers
+                .push((header_name.to_string(), value.to_string()));
+        } else if let Some(hash) = flag.strip_prefix("sha256=") {
+            sha256 = Some(parse_sha256(hash).ok_or_else(bad_flag)?);
+        } else if let Some(format) = flag.strip_prefix("format=") {
+            let path = match format.split_once(':') {
+                Some((kind, path)) => {
+                    (kind, Some(path.strip_prefix("path=").ok_or_else(bad_flag)?))
+                }
+                None => (format, None),
+            };
+            if !path.0.eq_ignore_ascii_case("json") {
+                return Err(bad_flag());
+            }
+            json_path = Some(path.1.unwrap_or_default().trim().to_string());
+        } else if !flag.is_empty() {
+            unknown_flags.push(flag.to_string());
+        }
+    }
 
-    Some(ParsedSource {
+    Ok(ParsedSource {
         url: url.to_string(),
         name: name.to_string(),
         category: category.to_string(),
+        enabled,
         allow_wildcards,
+        auto_categorize,
+        request,
+        sha256,
+        json_path,
+        unknown_flags,
     })
 }
 
-pub fn load_blocklists(config_file: &str, progress: &ProgressTracker) -> Result<Vec<Blocklist>> {
-    let path = Path::new(config_file);
-    if !path.exists() {
-        bail!("Configuration file '{config_file}' not found");
+/// Categories name directories under `base_dir` and files in `prod_dir`, so
+/// anything that could leave those directories (`..`, separators) or that
+/// the filesystem would reject (NUL, control characters) is refused.
+fn is_safe_category(category: &str) -> bool {
+    !category.is_empty()
+        && category != "."
+        && category != ".."
+        && !category
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control())
+}
+
+fn is_source_url(url: &str) -> bool {
+    Url::parse(url).is_ok() || local_source_path(url).is_some()
+}
+
+/// A pinned SHA-256, lowercased, if it is 64 hex digits.
+fn parse_sha256(hash: &str) -> Option<String> {
+    let hash = hash.trim();
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| hash.to_ascii_lowercase())
+}
+
+/// A `.toml` config: one `[[lists]]` table per list. Each table is kept
+/// loose here so one bad list is skipped on its own, with its line.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlConfig {
+    #[serde(default)]
+    lists: Vec<toml::Spanned<toml::Table>>,
+}
+
+/// One `[[lists]]` table; the keys mirror the pipe format's flags. Unknown
+/// keys are an error, so a misspelt `enabled` or `category` can't slip by.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlList {
+    url: String,
+    name: String,
+    category: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    abp: bool,
+    #[serde(default)]
+    auto: bool,
+    #[serde(default)]
+    post: bool,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    sha256: Option<String>,
+    #[serde(default)]
+    format: TomlFormat,
+    json_path: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TomlFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Map a `[[lists]]` table from a `.toml` config onto the same fields as a
+/// pipe-delimited line.
+fn toml_source(table: toml::Table) -> Result<ParsedSource, SkipReason> {
+    let list: TomlList = toml::Value::Table(table)
+        .try_into()
+        .map_err(|e: toml::de::Error| SkipReason::Toml(e.message().to_string()))?;
+    let bad_value = |key: &str| SkipReason::BadValue(key.to_string());
+
+    let url = list.url.trim();
+    let name = list.name.trim();
+    let category = list.category.trim();
+    if !is_source_url(url) {
+        return Err(SkipReason::BadUrl(url.to_string()));
+    }
+    if name.is_empty() {
+        return Err(bad_value("name"));
+    }
+    if !is_safe_category(category) {
+        return Err(SkipReason::BadCategory(category.to_string()));
     }
 
-    let content = std::fs::read_to_string(path)
-        .with_context(|| format!("Failed to read config file: {config_file}"))?;
+    let mut request = RequestSpec {
+        post: list.post,
+        ..RequestSpec::default()
+    };
+    for (header_name, value) in &list.headers {
+        let (header_name, value) = (header_name.trim(), value.trim());
+        if header_name.is_empty() {
+            return Err(bad_value("headers"));
+        }
+        if !RequestSpec::is_valid_header(header_name, value) {
+            return Err(SkipReason::BadHeader(header_name.to_string()));
+        }
+        request
+            .headers
+            .push((header_name.to_string(), value.to_string()));
+    }
 
-    let mut blocklists = Vec::new();
+    let sha256 = match &list.sha256 {
+        Some(hash) => Some(parse_sha256(hash).ok_or_else(|| bad_value("sha256"))?),
+        None => None,
+    };
+    let json_path = match (list.format, list.json_path) {
+        (TomlFormat::Json, path) => Some(path.unwrap_or_default().trim().to_string()),
+        (TomlFormat::Text, Some(_)) => return Err(bad_value("json_path")),
+        (TomlFormat::Text, None) => None,
+    };
+
+    Ok(ParsedSource {
+        url: url.to_string(),
+        name: name.to_string(),
+        category: category.to_string(),
+        enabled: list.enabled,
+        allow_wildcards: list.abp,
+        auto_categorize: list.auto,
+        request,
+        sha256,
+        json_path,
+        unknown_flags: Vec::new(),
+    })
+}
+
+/// Expand `${VAR}` in every string of a `.toml` list, as for config lines.
+fn expand_value(value: &mut toml::Value) -> Result<()> {
+    match value {
+        toml::Value::String(s) => *s = expand_env_vars(s, |var| std::env::var(var).ok())?,
+        toml::Value::Array(values) => {
+            for value in values {
+                expand_value(value)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                expand_value(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace each `${VAR}` with the value returned by `lookup`, so secrets like
+/// mirror tokens can stay out of the config file. Fails if a variable is unset.
+fn expand_env_vars(line: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let var = &rest[start + 2..start + 2 + len];
+        let Some(value) = lookup(var) else {
+            bail!("environment variable '{var}' is not set");
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&value);
+        rest = &rest[start + 3 + len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Distinct categories of `blocklists`, in `order`.
+pub fn ordered_categories(blocklists: &[Blocklist], order: CategoryOrder) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut categories: Vec<String> = blocklists
+        .iter()
+        .filter(|bl| seen.insert(bl.category.as_str()))
+        .map(|bl| bl.category.clone())
+        .collect();
+    if order == CategoryOrder::Alpha {
+        categories.sort();
+    }
+    categories
+}
+
+/// Parse a `--category-dir` value of the form `category=directory`.
+pub fn parse_category_dir(value: &str) -> Result<(String, String), String> {
+    let (category, dir) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected CATEGORY=DIR, got '{value}'"))?;
+    let category = normalize_category(category);
+    let dir = dir.trim();
+    if category.is_empty() || dir.is_empty() {
+        return Err(format!("expected CATEGORY=DIR, got '{value}'"));
+    }
+    Ok((category, dir.to_string()))
+}
+
+/// Parse a duration like `90s`, `30m`, `6h` or `2d`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let invalid = || format!("expected a number followed by s, m, h or d, got '{value}'");
+    let unit = value.chars().last().ok_or_else(invalid)?;
+    let number: u64 = value[..value.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// Categories are case-insensitive, so `Ads` and `ads` share one directory and output file.
+pub fn normalize_category(category: &str) -> String {
+    category.trim().to_lowercase()
+}
+
+/// How deep `include` directives may nest before the config is rejected.
+const MAX_INCLUDE_DEPTH: usize = 8;
 
+/// A non-comment config line, with env vars expanded, and where it came from.
+#[derive(Debug)]
+struct ConfigLine {
+    /// `12` for the top-level file, `base.conf:12` for an included one.
+    location: String,
+    /// The line as written, for messages (no expanded secrets).
+    raw: String,
+    text: String,
+}
+
+/// Read `path` into `out`, replacing each `include <path>` line with the
+/// lines of that file. Included paths are relative to the including file.
+/// `stack` holds the canonical paths currently being read, to catch cycles.
+fn read_config_lines(
+    path: &Path,
+    top_level: bool,
+    stack: &mut Vec<PathBuf>,
+    out: &mut Vec<ConfigLine>,
+) -> Result<()> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    if stack.contains(&canonical) {
+        bail!("Config include cycle: {} includes itself", path.display());
+    }
+    if stack.len() > MAX_INCLUDE_DEPTH {
+        bail!(
+            "Config includes nested more than {MAX_INCLUDE_DEPTH} deep at {}",
+            path.display()
+        );
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+
+    stack.push(canonical);
     for (line_num, line) in content.lines().enumerate() {
         let trimmed = line.trim();
         if trimmed.is_empty() || trimmed.starts_with('#') {
             continue;
         }
-
-        let Some(parsed) = parse_source_line(trimmed) else {
-            warn!("Invalid blocklist line {}: {line}", line_num + 1);
-            continue;
+        let location = if top_level {
+            (line_num + 1).to_string()
+        } else {
+            format!("{}:{}", path.display(), line_num + 1)
         };
 
-        let cached = progress.get(&parsed.name);
-        let etag = cached.and_then(|c| c.etag.clone());
-        let last_modified = cached.and_then(|c| c.last_modified.clone());
-
-        blocklists.push(Blocklist {
-            url: parsed.url,
-            name: parsed.name,
-            category: parsed.category,
-            allow_wildcards: parsed.allow_wildcards,
-            etag,
-            last_modified,
+        // Drop a trailing comment first, so `${VAR}` in it is not expanded
+        let text = strip_inline_comment(trimmed).trim_end();
+        let expanded = expand_env_vars(text, |var| std::env::var(var).ok())
+            .with_context(|| format!("Failed to expand blocklist line {location}"))?;
+        if let Some(target) = expanded
+            .strip_prefix("include")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+        {
+            let target = path.parent().unwrap_or(Path::new("")).join(target.trim());
+            read_config_lines(&target, false, stack, out)
+                .with_context(|| format!("Failed to include from line {location}"))?;
+            continue;
+        }
+        out.push(ConfigLine {
+            location,
+            raw: line.to_string(),
+            text: expanded,
         });
     }
+    stack.pop();
+    Ok(())
+}
+
+pub fn load_blocklists(
+    config_file: &str,
+    progress: &ProgressTracker,
+    allow_empty: bool,
+    strict: bool,
+) -> Result<Vec<Blocklist>> {
+    let (mut blocklists, _) = parse_blocklists(config_file, strict)?;
+    for bl in &mut blocklists {
+        if let Some(cached) = progress.get(&bl.name) {
+            bl.etag = cached.etag.clone();
+            bl.last_modified = cached.last_modified.clone();
+        }
+    }
 
     if blocklists.is_empty() {
+        if allow_empty {
+            info!("No valid blocklists found in configuration file, nothing to do");
+            return Ok(blocklists);
+        }
         bail!("No valid blocklists found in configuration file");
     }
 
     let categories: HashSet<&str> = blocklists.iter().map(|b| b.category.as_str()).collect();
+    let disabled = blocklists.iter().filter(|bl| !bl.enabled).count();
     info!(
-        "Loaded {} blocklists in {} categories",
+        "Loaded {} blocklists in {} categories{}",
         blocklists.len(),
-        categories.len()
+        categories.len(),
+        if disabled > 0 {
+            format!(" ({disabled} disabled)")
+        } else {
+            String::new()
+        }
     );
 
     Ok(blocklists)
 }
 
+/// `--list-categories`: print each category with its lists, then the lines
+/// skipped as invalid. Reads only the config (and its includes).
+pub fn print_categories(config_file: &str, order: CategoryOrder) -> Result<()> {
+    let (blocklists, skipped) = parse_blocklists(config_file, false)?;
+    let categories = ordered_categories(&blocklists, order);
+
+    println!("{}", "=".repeat(60));
+    println!("{:>35}", "CATEGORIES");
+    println!("{}", "=".repeat(60));
+    for cat in &categories {
+        let lists: Vec<&Blocklist> = blocklists.iter().filter(|bl| &bl.category == cat).collect();
+        println!(
+            "{cat} ({} list{})",
+            lists.len(),
+            if lists.len() == 1 { "" } else { "s" }
+        );
+        for bl in lists {
+            let state = if bl.enabled { "" } else { "  (disabled)" };
+            println!("  - {}  {}{state}", bl.name, bl.display_url);
+        }
+    }
+    println!("{}", "=".repeat(60));
+    println!(
+        "{} lists in {} categories",
+        blocklists.len(),
+        categories.len()
+    );
+    if !skipped.is_empty() {
+        println!("Skipped {} invalid line(s):", skipped.len());
+        for line in &skipped {
+            println!("  line {}: {} ({})", line.location, line.raw, line.reason);
+        }
+    }
+    Ok(())
+}
+
+/// A config line skipped as invalid.
+#[derive(Debug, PartialEq, Eq)]
+struct SkippedLine {
+    location: String,
+    /// The line as written, before `${VAR}` expansion.
+    raw: String,
+    reason: SkipReason,
+}
+
+/// A config entry as (location, line as written, URL as written, parse result).
+type ConfigSource = (String, String, String, Result<ParsedSource, SkipReason>);
+
+/// The URL field of a config line as written, before `${VAR}` expansion.
+fn written_url(raw: &str) -> String {
+    let fields = split_fields(strip_inline_comment(raw.trim()));
+    fields[0].trim().trim_start_matches('!').trim().to_string()
+}
+
+/// The `[[lists]]` tables of a `.toml` config, each labelled with the line of
+/// its header.
+fn toml_sources(path: &Path) -> Result<Vec<ConfigSource>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    let config: TomlConfig = toml::from_str(&content)
+        .with_context(|| format!("Invalid TOML config {}", path.display()))?;
+
+    let mut sources = Vec::new();
+    for list in config.lists {
+        let line = content[..list.span().start].matches('\n').count() + 1;
+        let location = line.to_string();
+        let mut table = list.into_inner();
+        // Labelled before expansion, like config lines, so no secrets are logged
+        let raw = match table.get("name").and_then(toml::Value::as_str) {
+            Some(name) => format!("[[lists]] {name}"),
+            None => "[[lists]]".to_string(),
+        };
+        let url = table
+            .get("url")
+            .and_then(toml::Value::as_str)
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        for (_, value) in table.iter_mut() {
+            expand_value(value)
+                .with_context(|| format!("Failed to expand list at line {location}"))?;
+        }
+        sources.push((location, raw, url, toml_source(table)));
+    }
+    Ok(sources)
+}
+
+/// The blocklists in `config_file`, includes expanded, without cached
+/// validators, plus each line skipped as invalid.
+fn parse_blocklists(config_file: &str, strict: bool) -> Result<(Vec<Blocklist>, Vec<SkippedLine>)> {
+    let path = Path::new(config_file);
+    if !path.exists() {
+        bail!("Configuration file '{config_file}' not found");
+    }
+
+    let is_toml = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+    let sources = if is_toml {
+        toml_sources(path)?
+    } else {
+        let mut lines = Vec::new();
+        read_config_lines(path, true, &mut Vec::new(), &mut lines)?;
+        lines
+            .into_iter()
+            .map(|line| {
+                let url = written_url(&line.raw);
+                (line.location, line.raw, url, parse_source_line(&line.text))
+            })
+            .collect()
+    };
+
+    let mut blocklists = Vec::new();
+    // Normalized category -> first spelling seen, for reporting merges
+    let mut category_spellings: HashMap<String, String> = HashMap::new();
+    let mut skipped = Vec::new();
+
+    for (location, raw, display_url, parsed) in sources {
+        // An unknown flag is most likely a typo: loaded with a warning, but
+        // invalid under --strict-config
+        let parsed = parsed.and_then(|parsed| match parsed.unknown_flags.first() {
+            Some(flag) if strict => Err(SkipReason::BadFlag(flag.clone())),
+            _ => Ok(parsed),
+        });
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err(reason) => {
+                if strict {
+                    error!("Invalid blocklist line {location} ({reason}): {raw}");
+                } else {
+                    warn!("Invalid blocklist line {location} ({reason}): {raw}");
+                }
+                skipped.push(SkippedLine {
+                    location,
+                    raw,
+                    reason,
+                });
+                continue;
+            }
+        };
+
+        for flag in &parsed.unknown_flags {
+            warn!("Line {location}: unknown flag '{flag}' ignored");
+        }
+
+        let category = normalize_category(&parsed.category);
+        let first_spelling = category_spellings
+            .entry(category.clone())
+            .or_insert_with(|| parsed.category.clone());
+        if *first_spelling != parsed.category {
+            warn!(
+                "Line {location}: category '{}' merged with '{first_spelling}' as '{category}'",
+                parsed.category
+            );
+        }
+
+        blocklists.push(Blocklist {
+            url: parsed.url,
+            display_url,
+            name: parsed.name,
+            category,
+            enabled: parsed.enabled,
+            allow_wildcards: parsed.allow_wildcards,
+            auto_categorize: parsed.auto_categorize,
+            request: parsed.request,
+            sha256: parsed.sha256,
+            json_path: parsed.json_path,
+            etag: None,
+            last_modified: None,
+        });
+    }
+
+    if strict && !skipped.is_empty() {
+        bail!(
+            "{} invalid blocklist line(s) in configuration (--strict-config)",
+            skipped.len()
+        );
+    }
+
+    Ok((blocklists, skipped))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
+
+    fn write_conf(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_blocklists_reports_skipped_lines() {
+        let dir = TempDir::new("parse");
+        let conf = write_conf(
+            &dir,
+            "lists.conf",
+            "# comment\nhttps://a.example/1.txt|one|ads\nnot a source\nhttps://a.example/2.txt|two|Tracking\n",
+        );
+
+        let (blocklists, skipped) = parse_blocklists(conf.to_str().unwrap(), false).unwrap();
+        let names: Vec<(&str, &str)> = blocklists
+            .iter()
+            .map(|bl| (bl.name.as_str(), bl.category.as_str()))
+            .collect();
+        assert_eq!(names, [("one", "ads"), ("two", "tracking")]);
+        assert_eq!(
+            skipped,
+            [SkippedLine {
+                location: "3".to_string(),
+                raw: "not a source".to_string(),
+                reason: SkipReason::FieldCount(1),
+            }]
+        );
+        assert!(parse_blocklists(conf.to_str().unwrap(), true).is_err());
+    }
+
+    #[test]
+    fn toml_config_maps_to_blocklists() {
+        let dir = TempDir::new("toml");
+        let conf = write_conf(
+            &dir,
+            "lists.toml",
+            r#"[[lists]]
+url = "https://a.example/1.txt"
+name = "one"
+category = "Ads"
+abp = true
+headers.Authorization = "Bearer x"
+
+[[lists]]
+url = "https://a.example/off.txt"
+name = "off"
+category = "ads"
+enabled = false
+
+[[lists]]
+url = "https://a.example/feed"
+name = "feed"
+category = "malicious"
+format = "json"
+json_path = "data.domains"
+
+[[lists]]
+url = "https://a.example/3.txt"
+name = "three"
+
+[[lists]]
+url = "https://a.example/4.txt"
+name = "four"
+category = "ads"
+enabeld = false
+"#,
+        );
+
+        let (blocklists, skipped) = parse_blocklists(conf.to_str().unwrap(), false).unwrap();
+        let enabled: Vec<(&str, bool)> = blocklists
+            .iter()
+            .map(|bl| (bl.name.as_str(), bl.enabled))
+            .collect();
+        assert_eq!(enabled, [("one", true), ("off", false), ("feed", true)]);
+        let one = &blocklists[0];
+        assert_eq!((one.name.as_str(), one.category.as_str()), ("one", "ads"));
+        assert!(one.allow_wildcards);
+        assert_eq!(
+            one.request.headers,
+            [("Authorization".to_string(), "Bearer x".to_string())]
+        );
+        assert_eq!(blocklists[2].json_path.as_deref(), Some("data.domains"));
+        let reasons: Vec<(&str, &str, String)> = skipped
+            .iter()
+            .map(|s| (s.location.as_str(), s.raw.as_str(), s.reason.to_string()))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (
+                    "21",
+                    "[[lists]] three",
+                    "missing field `category`".to_string()
+                ),
+                (
+                    "25",
+                    "[[lists]] four",
+                    "unknown field `enabeld`, expected one of `url`, `name`, `category`, \
+                     `enabled`, `abp`, `auto`, `post`, `headers`, `sha256`, `format`, \
+                     `json_path`"
+                        .to_string()
+                ),
+            ]
+        );
+        assert!(parse_blocklists(conf.to_str().unwrap(), true).is_err());
+    }
+
+    fn toml_table(extra: &str) -> toml::Table {
+        toml::from_str(&format!(
+            "url = \"https://a.example/x\"\nname = \"x\"\ncategory = \"ads\"\n{extra}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn toml_source_rejects_bad_values() {
+        let reason = |extra: &str| toml_source(toml_table(extra)).unwrap_err();
+        assert_eq!(
+            reason("abp = \"yes\""),
+            SkipReason::Toml("invalid type: string \"yes\", expected a boolean".into())
+        );
+        assert_eq!(
+            reason("sha256 = \"abc\""),
+            SkipReason::BadValue("sha256".into())
+        );
+        assert!(matches!(
+            reason("format = \"csv\""),
+            SkipReason::Toml(message) if message.starts_with("unknown variant `csv`")
+        ));
+        assert_eq!(
+            reason("json_path = \"a\""),
+            SkipReason::BadValue("json_path".into())
+        );
+        assert!(matches!(
+            reason("headers = { X = true }"),
+            SkipReason::Toml(_)
+        ));
+        assert!(toml_source(toml_table("format = \"json\"\njson_path = \"a.b\"")).is_ok());
+    }
+
+    #[test]
+    fn include_expands_relative_to_including_file() {
+        let dir = TempDir::new("include");
+        std::fs::create_dir_all(dir.join("env")).unwrap();
+        write_conf(&dir, "base.conf", "https://a.example/1.txt|one|ads\n");
+        write_conf(
+            &dir.join("env"),
+            "prod.conf",
+            "# extras\ninclude ../base.conf\nhttps://a.example/2.txt|two|tracking\n",
+        );
+        let top = write_conf(
+            &dir,
+            "top.conf",
+            "include env/prod.conf\nhttps://a.example/3.txt|three|ads\n",
+        );
+
+        let mut lines = Vec::new();
+        read_config_lines(&top, true, &mut Vec::new(), &mut lines).unwrap();
+        let texts: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "https://a.example/1.txt|one|ads",
+                "https://a.example/2.txt|two|tracking",
+                "https://a.example/3.txt|three|ads",
+            ]
+        );
+        assert_eq!(lines[2].location, "2");
+        assert!(lines[0].location.ends_with("base.conf:1"));
+    }
+
+    #[test]
+    fn inline_comment_is_stripped_before_env_expansion() {
+        let dir = TempDir::new("comment-env");
+        let path = write_conf(
+            &dir,
+            "c.conf",
+            "https://a.example/1.txt|one|ads # was ${PIHOLE_UNSET_TOKEN}\n",
+        );
+
+        let mut lines = Vec::new();
+        read_config_lines(&path, true, &mut Vec::new(), &mut lines).unwrap();
+        assert_eq!(lines[0].text, "https://a.example/1.txt|one|ads");
+    }
+
+    #[test]
+    fn display_url_keeps_env_vars_unexpanded() {
+        assert_eq!(
+            written_url("!https://a.example/${TOKEN}/x\\|y.txt|one|ads # note"),
+            "https://a.example/${TOKEN}/x|y.txt"
+        );
+
+        std::env::set_var("PIHOLE_TEST_FEED_KEY", "s3cret");
+        let dir = TempDir::new("display-url");
+        let conf = write_conf(
+            &dir,
+            "c.conf",
+            "https://a.example/feed?key=${PIHOLE_TEST_FEED_KEY}|feed|ads\n",
+        );
+        let toml = write_conf(
+            &dir,
+            "c.toml",
+            "[[lists]]\nurl = \"https://a.example/${PIHOLE_TEST_FEED_KEY}\"\nname = \"t\"\ncategory = \"ads\"\n",
+        );
+        for (path, written, expanded) in [
+            (
+                &conf,
+                "https://a.example/feed?key=${PIHOLE_TEST_FEED_KEY}",
+                "https://a.example/feed?key=s3cret",
+            ),
+            (
+                &toml,
+                "https://a.example/${PIHOLE_TEST_FEED_KEY}",
+                "https://a.example/s3cret",
+            ),
+        ] {
+            let (blocklists, _) = parse_blocklists(path.to_str().unwrap(), false).unwrap();
+            assert_eq!(blocklists[0].url, expanded);
+            assert_eq!(blocklists[0].display_url, written);
+        }
+    }
+
+    #[test]
+    fn strict_config_fails_after_reporting_every_bad_line() {
+        let dir = TempDir::new("strict");
+        let path = write_conf(
+            &dir,
+            "c.conf",
+            "https://a.example/1.txt|one|ads\nnot a url|two|ads\nhttps://a.example/3.txt|three\n",
+        );
+        let path = path.to_str().unwrap();
+        let progress = ProgressTracker::load_from(&dir.join("progress.json"));
+
+        assert_eq!(
+            load_blocklists(path, &progress, false, false)
+                .unwrap()
+                .len(),
+            1
+        );
+        let err = load_blocklists(path, &progress, false, true).unwrap_err();
+        assert!(err.to_string().starts_with("2 invalid"), "{err}");
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let dir = TempDir::new("include-cycle");
+        write_conf(&dir, "a.conf", "include b.conf\n");
+        let b = write_conf(&dir, "b.conf", "include a.conf\n");
+        let self_ref = write_conf(&dir, "self.conf", "include self.conf\n");
+
+        for path in [b, self_ref] {
+            let err = read_config_lines(&path, true, &mut Vec::new(), &mut Vec::new()).unwrap_err();
+            assert!(format!("{err:#}").contains("cycle"), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn orders_categories_by_config_or_name() {
+        let blocklists: Vec<Blocklist> = [("a", "tracking"), ("b", "ads"), ("c", "tracking")]
+            .into_iter()
+            .map(|(name, category)| Blocklist {
+                url: format!("https://example.com/{name}.txt"),
+                display_url: format!("https://example.com/{name}.txt"),
+                name: name.to_string(),
+                category: category.to_string(),
+                enabled: true,
+                allow_wildcards: false,
+                auto_categorize: false,
+                request: RequestSpec::default(),
+                sha256: None,
+                json_path: None,
+                etag: None,
+                last_modified: None,
+            })
+            .collect();
+        assert_eq!(
+            ordered_categories(&blocklists, CategoryOrder::Config),
+            ["tracking", "ads"]
+        );
+        assert_eq!(
+            ordered_categories(&blocklists, CategoryOrder::Alpha),
+            ["ads", "tracking"]
+        );
+    }
 
     #[test]
     fn parses_three_field_line_without_wildcards() {
@@ -150,16 +1129,228 @@ mod tests {
         assert!(!p.allow_wildcards);
     }
 
+    #[test]
+    fn unknown_flags_load_unless_strict() {
+        let p = parse_source_line("https://example.com/a.txt|name|advertising|abp|atuo|").unwrap();
+        assert!(p.allow_wildcards);
+        assert_eq!(p.unknown_flags, ["atuo"]);
+
+        let dir = TempDir::new("unknown-flag");
+        let conf = write_conf(&dir, "c.conf", "https://a.example/1.txt|one|ads|atuo\n");
+        let (blocklists, skipped) = parse_blocklists(conf.to_str().unwrap(), false).unwrap();
+        assert_eq!((blocklists.len(), skipped.len()), (1, 0));
+        assert!(parse_blocklists(conf.to_str().unwrap(), true).is_err());
+    }
+
+    #[test]
+    fn parses_method_and_header_flags() {
+        let p = parse_source_line(
+            "https://api.example.com/feed|feed|malicious|post|header=X-Api-Key: abc:123|abp",
+        )
+        .unwrap();
+        assert!(p.allow_wildcards);
+        assert!(p.request.post);
+        assert_eq!(
+            p.request.headers,
+            [("X-Api-Key".to_string(), "abc:123".to_string())]
+        );
+
+        let plain = parse_source_line("https://example.com/a.txt|name|advertising").unwrap();
+        assert_eq!(plain.request, RequestSpec::default());
+        assert!(
+            parse_source_line("https://example.com/a.txt|name|advertising|header=NoColon").is_err()
+        );
+        assert_eq!(
+            parse_source_line("https://example.com/a.txt|name|advertising|header=X Key: s3cret")
+                .unwrap_err(),
+            SkipReason::BadHeader("X Key".to_string())
+        );
+        let reason = toml_source(toml_table("headers.\"X-Key\" = \"a\\u0001b\"")).unwrap_err();
+        assert_eq!(reason, SkipReason::BadHeader("X-Key".to_string()));
+    }
+
+    #[test]
+    fn parses_sha256_flag() {
+        let hash = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        let p = parse_source_line(&format!(
+            "https://example.com/a.txt|name|malicious|sha256={hash}"
+        ))
+        .unwrap();
+        assert_eq!(p.sha256.as_deref(), Some(hash.to_lowercase().as_str()));
+
+        assert!(
+            parse_source_line("https://example.com/a.txt|name|malicious|sha256=abc123").is_err()
+        );
+    }
+
+    #[test]
+    fn parses_json_format_flag() {
+        let p = parse_source_line("https://example.com/feed|feed|malicious|format=json").unwrap();
+        assert_eq!(p.json_path.as_deref(), Some(""));
+        let p = parse_source_line(
+            "https://example.com/feed|feed|malicious|format=json:path=data.domains",
+        )
+        .unwrap();
+        assert_eq!(p.json_path.as_deref(), Some("data.domains"));
+
+        assert!(parse_source_line("https://example.com/feed|feed|malicious|format=csv").is_err());
+        assert!(
+            parse_source_line("https://example.com/feed|feed|malicious|format=json:domains")
+                .is_err()
+        );
+    }
+
     #[test]
     fn skips_comment_and_blank_lines() {
-        assert!(parse_source_line("# comment").is_none());
-        assert!(parse_source_line("   ").is_none());
+        assert!(parse_source_line("# comment").is_err());
+        assert!(parse_source_line("   ").is_err());
+    }
+
+    #[test]
+    fn strips_trailing_comment() {
+        let p = parse_source_line("https://example.com/a.txt|name|advertising # note").unwrap();
+        assert_eq!(p.category, "advertising");
+        let p =
+            parse_source_line("https://example.com/a.txt|name|advertising|abp\t# note").unwrap();
+        assert!(p.allow_wildcards);
+    }
+
+    #[test]
+    fn keeps_hash_inside_url() {
+        let p = parse_source_line("https://example.com/a.txt#v2|name|advertising").unwrap();
+        assert_eq!(p.url, "https://example.com/a.txt#v2");
+    }
+
+    #[test]
+    fn expands_env_vars_in_url() {
+        let lookup = |var: &str| (var == "TOKEN").then(|| "s3cret".to_string());
+        let line =
+            expand_env_vars("https://mirror.example/${TOKEN}/a.txt|name|ads", lookup).unwrap();
+        assert_eq!(line, "https://mirror.example/s3cret/a.txt|name|ads");
+        let p = parse_source_line(&line).unwrap();
+        assert_eq!(p.url, "https://mirror.example/s3cret/a.txt");
+
+        assert_eq!(
+            expand_env_vars("https://a.com/$x/{y}", lookup).unwrap(),
+            "https://a.com/$x/{y}"
+        );
+        assert_eq!(
+            expand_env_vars("https://a.com/${TOKEN", lookup).unwrap(),
+            "https://a.com/${TOKEN"
+        );
+    }
+
+    #[test]
+    fn unset_env_var_is_an_error() {
+        let err = expand_env_vars("https://a.com/${MISSING}/a.txt", |_| None).unwrap_err();
+        assert!(err.to_string().contains("MISSING"));
+    }
+
+    #[test]
+    fn escaped_pipe_stays_in_url() {
+        let p = parse_source_line(r"https://example.com/a\|b.txt|name|advertising").unwrap();
+        assert_eq!(p.url, "https://example.com/a|b.txt");
+        assert_eq!(p.name, "name");
+        assert_eq!(p.category, "advertising");
+
+        let p = parse_source_line(r"https://example.com/a\\|name|advertising").unwrap();
+        assert_eq!(p.url, r"https://example.com/a\");
+        assert_eq!(p.name, "name");
+    }
+
+    #[test]
+    fn parses_category_dir() {
+        assert_eq!(
+            parse_category_dir("Ads=/mnt/ads").unwrap(),
+            ("ads".to_string(), "/mnt/ads".to_string())
+        );
+        assert_eq!(
+            parse_category_dir("malicious=/data/a=b").unwrap(),
+            ("malicious".to_string(), "/data/a=b".to_string())
+        );
+        assert!(parse_category_dir("ads").is_err());
+        assert!(parse_category_dir("=/mnt/ads").is_err());
+        assert!(parse_category_dir("ads=").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration(" 6h"), Ok(Duration::from_secs(6 * 3600)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(2 * 86400)));
+        for bad in ["", "h", "6", "6x", "-1h", "1.5h", "6é"] {
+            assert!(parse_duration(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn normalizes_category_case() {
+        assert_eq!(normalize_category("Ads"), "ads");
+        assert_eq!(normalize_category(" ADS "), "ads");
+        assert_eq!(normalize_category("ads"), "ads");
+    }
+
+    #[test]
+    fn leading_bang_disables_a_list() {
+        let p = parse_source_line("!https://example.com/a.txt|name|advertising").unwrap();
+        assert!(!p.enabled);
+        assert_eq!(p.url, "https://example.com/a.txt");
+        assert!(
+            parse_source_line("https://example.com/a.txt|name|advertising")
+                .unwrap()
+                .enabled
+        );
+        assert_eq!(
+            parse_source_line("!nope|name|advertising").unwrap_err(),
+            SkipReason::BadUrl("nope".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_categories_that_are_not_plain_names() {
+        for category in ["../evil", "foo/bar", "..", "a\\b", "nul\0", ""] {
+            assert_eq!(
+                parse_source_line(&format!("https://example.com/a.txt|name|{category}"))
+                    .unwrap_err(),
+                SkipReason::BadCategory(category.to_string()),
+                "{category:?}"
+            );
+        }
+        assert!(parse_source_line("https://example.com/a.txt|name|my-ads.v2").is_ok());
+
+        let table: toml::Table =
+            toml::from_str("url = \"https://a.example/x\"\nname = \"x\"\ncategory = \"../evil\"\n")
+                .unwrap();
+        assert_eq!(
+            toml_source(table).unwrap_err(),
+            SkipReason::BadCategory("../evil".to_string())
+        );
     }
 
     #[test]
     fn rejects_bad_field_counts_and_urls() {
-        assert!(parse_source_line("a|b").is_none());
-        assert!(parse_source_line("a|b|c|d|e").is_none());
-        assert!(parse_source_line("not-a-url|n|advertising").is_none());
+        assert_eq!(
+            parse_source_line("a|b").unwrap_err(),
+            SkipReason::FieldCount(2)
+        );
+        assert_eq!(
+            parse_source_line("https://example.com/a.txt|name").unwrap_err(),
+            SkipReason::FieldCount(2)
+        );
+        assert_eq!(
+            parse_source_line("a|b|c|d|e").unwrap_err(),
+            SkipReason::BadUrl("a".to_string())
+        );
+        assert_eq!(
+            parse_source_line("not-a-url|n|advertising").unwrap_err(),
+            SkipReason::BadUrl("not-a-url".to_string())
+        );
+        assert_eq!(
+            parse_source_line("https://example.com/a.txt|n|malicious|sha256=abc")
+                .unwrap_err()
+                .to_string(),
+            "invalid flag: sha256=abc"
+        );
     }
 }
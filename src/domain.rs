@@ -1,40 +1,132 @@
 use regex::Regex;
-use std::sync::LazyLock;
+use std::collections::HashSet;
+use std::sync::{LazyLock, OnceLock};
+use unicode_script::{Script, UnicodeScript};
 
 const MAX_DOMAIN_LENGTH: usize = 253;
 
-static DOMAIN_RE: LazyLock<Regex> = LazyLock::new(|| {
+/// Characters `--allow-chars` may not add, because the list formats use them
+/// as syntax (wildcards, ABP anchors, comments, hosts separators).
+const RESERVED_CHARS: &str = ".*/#!|^$@:;,=?[]()\\\"'`";
+
+/// Which characters domain labels may contain. Strict (the default) allows
+/// letters, digits and inner hyphens; lenient policies add extra characters,
+/// such as `_` for service records, anywhere in a label.
+pub struct DomainPolicy {
+    re: Regex,
+}
+
+impl DomainPolicy {
+    pub fn strict() -> Self {
+        Self::with_extra_chars("")
+    }
+
+    pub fn with_extra_chars(extra: &str) -> Self {
+        let extra = regex::escape(extra);
+        let edge = format!("[a-zA-Z0-9{extra}]");
+        let inner = format!("[a-zA-Z0-9{extra}-]");
+        let label = format!("{edge}(?:{inner}{{0,61}}{edge})?");
+        let last = format!("{edge}{inner}{{0,61}}{edge}");
+        Self {
+            re: Regex::new(&format!(r"^(?:{label}\.)+{last}$")).unwrap(),
+        }
+    }
+
+    pub fn validate(&self, domain: &str) -> bool {
+        if domain.is_empty() || domain == "localhost" || domain.ends_with(".local") {
+            return false;
+        }
+        if domain.len() > MAX_DOMAIN_LENGTH {
+            return false;
+        }
+        let check = if let Some(stripped) = domain.strip_prefix("*.") {
+            stripped
+        } else {
+            domain
+        };
+        self.re.is_match(check)
+    }
+}
+
+static DOMAIN_POLICY: OnceLock<DomainPolicy> = OnceLock::new();
+
+/// Install the process-wide policy used by `validate_domain`. Only the first
+/// call takes effect, so set it at startup before any parsing.
+pub fn set_domain_policy(policy: DomainPolicy) -> bool {
+    DOMAIN_POLICY.set(policy).is_ok()
+}
+
+/// Parse an `--allow-chars` value: printable ASCII characters that are not
+/// letters, digits, whitespace or list syntax.
+pub fn parse_allow_chars(value: &str) -> Result<String, String> {
+    for c in value.chars() {
+        if !c.is_ascii_graphic() || c.is_ascii_alphanumeric() || c == '-' {
+            return Err(format!("'{c}' is not an extra ASCII symbol"));
+        }
+        if RESERVED_CHARS.contains(c) {
+            return Err(format!("'{c}' is reserved by the list formats"));
+        }
+    }
+    Ok(value.to_string())
+}
+
+static ADBLOCK_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\|\|(.+?)\^(?:\$(.*))?$").unwrap());
+
+/// Addresses a `$dnsrewrite` A/AAAA rule may point at and still count as a block.
+const SINKHOLE_IPS: &[&str] = &["0.0.0.0", "127.0.0.1", "::", "::1"];
+
+/// Hosts-file line: IPv4 or IPv6 address, any run of spaces/tabs, then the first
+/// hostname. Anything after it is captured by `extract_entries`, not here.
+static IP_DOMAIN_RE: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(
-        r"^(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?\.)+[a-zA-Z0-9][a-zA-Z0-9-]{0,61}[a-zA-Z0-9]$",
+        r"^(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}|[0-9a-fA-F:.]*:[0-9a-fA-F:.]*(?:%[0-9A-Za-z_.-]+)?)\s+(\S+)(?:\s.*)?$",
     )
     .unwrap()
 });
 
-static ADBLOCK_RE: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"^\|\|(.+?)\^(?:\$.*)?$").unwrap());
-
-static IP_DOMAIN_RE: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"^\s*\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}\s+(\S+)$").unwrap());
+/// Split a hosts-file line into its address and first hostname. IPv6 addresses
+/// (`::`, `fe80::1`, `fe80::1%eth0`, `::ffff:1.2.3.4`) must parse as real
+/// addresses, since the pattern alone would accept any run of hex and colons.
+fn hosts_entry(line: &str) -> Option<(&str, &str)> {
+    let caps = IP_DOMAIN_RE.captures(line)?;
+    let ip = caps.get(1)?.as_str();
+    if ip.contains(':') {
+        let addr = ip.split_once('%').map_or(ip, |(addr, _)| addr);
+        addr.parse::<std::net::Ipv6Addr>().ok()?;
+    }
+    Some((ip, caps.get(2)?.as_str()))
+}
 
 static COMMENT_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[#!].*$").unwrap());
 
 pub fn validate_domain(domain: &str) -> bool {
-    if domain.is_empty() || domain == "localhost" || domain.ends_with(".local") {
-        return false;
-    }
-    if domain.len() > MAX_DOMAIN_LENGTH {
-        return false;
-    }
-    let check = if let Some(stripped) = domain.strip_prefix("*.") {
-        stripped
-    } else {
-        domain
-    };
-    DOMAIN_RE.is_match(check)
+    DOMAIN_POLICY
+        .get_or_init(DomainPolicy::strict)
+        .validate(domain)
 }
 
+/// Lowercase `domain` and drop a trailing dot. Unicode (IDN) names are
+/// converted to their punycode form, so `münchen.de` and `xn--mnchen-3ya.de`
+/// are the same entry; a name that can't be converted is left for
+/// `validate_domain` to reject.
 pub fn normalize_domain(domain: &str) -> String {
-    domain.to_lowercase().trim_end_matches('.').to_string()
+    let domain = domain.trim_end_matches('.');
+    if !domain.is_ascii() {
+        if let Ok(ascii) = idna::domain_to_ascii(domain) {
+            return ascii;
+        }
+    }
+    domain.to_lowercase()
+}
+
+/// Each parent of `domain`, nearest first: `a.b.example.com` yields
+/// `b.example.com`, `example.com`, `com`. Zero-allocation: yields suffixes
+/// after each dot.
+pub fn parent_domains(domain: &str) -> impl Iterator<Item = &str> {
+    domain
+        .match_indices('.')
+        .map(move |(i, _)| &domain[i + 1..])
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -50,6 +142,20 @@ impl Entry {
             Entry::Wildcard(d) => format!("||{d}^"),
         }
     }
+
+    /// Inverse of `to_key`.
+    pub fn from_key(key: &str) -> Entry {
+        match key.strip_prefix("||").and_then(|k| k.strip_suffix('^')) {
+            Some(d) => Entry::Wildcard(d.to_string()),
+            None => Entry::Exact(key.to_string()),
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            Entry::Exact(d) | Entry::Wildcard(d) => d,
+        }
+    }
 }
 
 fn make_exact(domain: &str) -> Option<Entry> {
@@ -70,23 +176,64 @@ fn make_wildcard(domain: &str) -> Option<Entry> {
     }
 }
 
+/// Whether an ABP rule's `$modifiers` still block the domain. Only a
+/// `dnsrewrite` to an error rcode or a sinkhole address counts; rewrites to real
+/// addresses or other hosts are redirects, not blocks. Rules without
+/// `dnsrewrite` are unaffected.
+fn dnsrewrite_blocks(modifiers: &str) -> bool {
+    let Some(value) = modifiers.split(',').find_map(|m| {
+        let m = m.trim();
+        m.strip_prefix("dnsrewrite=")
+            .or_else(|| (m == "dnsrewrite").then_some(""))
+    }) else {
+        return true;
+    };
+
+    let parts: Vec<&str> = value.split(';').map(str::trim).collect();
+    match parts.as_slice() {
+        // Full form: RCODE;RRTYPE;VALUE
+        [rcode, rrtype, target] => {
+            if !rcode.eq_ignore_ascii_case("NOERROR") {
+                return true;
+            }
+            (rrtype.eq_ignore_ascii_case("A") || rrtype.eq_ignore_ascii_case("AAAA"))
+                && SINKHOLE_IPS.contains(target)
+        }
+        // Short form: an rcode, an IP address, or a CNAME target
+        [short] => {
+            ["NXDOMAIN", "REFUSED", "SERVFAIL"]
+                .iter()
+                .any(|rcode| short.eq_ignore_ascii_case(rcode))
+                || SINKHOLE_IPS.contains(short)
+        }
+        _ => false,
+    }
+}
+
 pub fn extract_entry(line: &str, allow_wildcards: bool) -> Option<Entry> {
     let line = line.trim();
     if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
         return None;
     }
 
+    // Strip inline comments before matching so `0.0.0.0 ads.com # note` keeps
+    // only the hostname, then trim the whitespace the comment leaves behind.
     let line = COMMENT_RE.replace(line, "");
     let line = line.trim();
     if line.is_empty() {
         return None;
     }
 
-    if let Some(caps) = IP_DOMAIN_RE.captures(line) {
-        return make_exact(caps.get(1)?.as_str());
+    if let Some((_, host)) = hosts_entry(line) {
+        return make_exact(host);
     }
 
     if let Some(caps) = ADBLOCK_RE.captures(line) {
+        if let Some(modifiers) = caps.get(2) {
+            if !dnsrewrite_blocks(modifiers.as_str()) {
+                return None;
+            }
+        }
         let domain = caps.get(1)?.as_str();
         return if allow_wildcards {
             make_wildcard(domain)
@@ -110,6 +257,161 @@ pub fn extract_entry(line: &str, allow_wildcards: bool) -> Option<Entry> {
     None
 }
 
+/// Every entry on `line`. A hosts line may name several hostnames after its
+/// address (`0.0.0.0 ads.com track.com`), each of which is returned; tokens
+/// that aren't valid domains are skipped. Other lines yield at most the one
+/// entry `extract_entry` finds.
+pub fn extract_entries(line: &str, allow_wildcards: bool) -> Vec<Entry> {
+    let trimmed = line.trim();
+    if !trimmed.starts_with('#') {
+        let uncommented = COMMENT_RE.replace(trimmed, "");
+        let uncommented = uncommented.trim();
+        if hosts_entry(uncommented).is_some() {
+            return uncommented
+                .split_whitespace()
+                .skip(1)
+                .filter_map(make_exact)
+                .collect();
+        }
+    }
+    extract_entry(line, allow_wildcards).into_iter().collect()
+}
+
+/// Recover the host from a hosts line whose hostname is really a URL or
+/// `host:port`, e.g. `0.0.0.0 http://ads.example.com/path`. Returns `None` for
+/// lines that aren't hosts entries or have nothing to strip.
+pub fn salvage_hosts_url(line: &str) -> Option<Entry> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let (_, token) = hosts_entry(line)?;
+    let host = token.split_once("://").map_or(token, |(_, rest)| rest);
+    let host = host.split(['/', '?', '#']).next().unwrap_or(host);
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    };
+    if host == token {
+        return None;
+    }
+    make_exact(host)
+}
+
+/// Whether `line` is an ABP rule scoped to particular DNS record types
+/// (`||domain^$dnstype=HTTPS`), which blocks only those queries rather than the
+/// whole domain.
+pub fn has_dnstype_modifier(line: &str) -> bool {
+    ADBLOCK_RE
+        .captures(line.trim())
+        .and_then(|caps| caps.get(2))
+        .is_some_and(|modifiers| {
+            modifiers
+                .as_str()
+                .split(',')
+                .any(|m| m.trim().starts_with("dnstype="))
+        })
+}
+
+/// The address a hosts-file line points its domain at, or `None` if `line` is
+/// not a hosts entry (plain domain, ABP rule, comment).
+pub fn hosts_line_ip(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    hosts_entry(line).map(|(ip, _)| ip)
+}
+
+/// The line itself if it is a Pi-hole regex filter such as
+/// `(^|\.)doubleclick\.net$` or `^ad[sx]?[0-9]*\.;querytype=A`.
+///
+/// Lines must contain an escaped dot and a literal label fragment (see
+/// `has_domain_fragment`), contain no whitespace, and compile once any
+/// `;option` suffix is removed. A pattern that matches the empty string or a
+/// single-label name like `a` would block everything and is refused, so stray
+/// `^`, `$` or `(.*)` lines never become filters. ABP rules, hosts entries and
+/// comments never match.
+pub fn pihole_regex(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.is_empty()
+        || line.starts_with(['#', '!', '|', '@', '/'])
+        || line.contains(char::is_whitespace)
+    {
+        return None;
+    }
+    let pattern = line.split(';').next().unwrap_or(line);
+    if !has_domain_fragment(pattern) {
+        return None;
+    }
+    let regex = Regex::new(pattern).ok()?;
+    if regex.is_match("") || regex.is_match("a") {
+        return None;
+    }
+    Some(line)
+}
+
+/// True if `pattern` has an escaped dot (`\.`) and at least two literal
+/// label characters in a row outside any escape, `[...]` class or `{...}`
+/// repetition, as in `doubleclick\.net` or `^ad[sx]?\.`.
+fn has_domain_fragment(pattern: &str) -> bool {
+    if !pattern.contains("\\.") {
+        return false;
+    }
+    let mut run = 0;
+    let mut close = None;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if let Some(end) = close {
+            if c == end {
+                close = None;
+            }
+            continue;
+        }
+        match c {
+            '\\' => {
+                chars.next();
+                run = 0;
+            }
+            '[' => close = Some(']'),
+            '{' => close = Some('}'),
+            c if c.is_ascii_alphanumeric() || c == '-' => {
+                run += 1;
+                if run >= 2 {
+                    return true;
+                }
+            }
+            _ => run = 0,
+        }
+        if close.is_some() {
+            run = 0;
+        }
+    }
+    false
+}
+
+/// True if any label of `domain` mixes writing systems once punycode (`xn--`)
+/// labels are decoded, e.g. Latin and Cyrillic in `xn--pple-43d.com` (аpple.com).
+/// Such domains are common in homograph phishing.
+pub fn is_mixed_script(domain: &str) -> bool {
+    if !domain.contains("xn--") {
+        return false;
+    }
+    let (decoded, result) = idna::domain_to_unicode(domain);
+    if result.is_err() {
+        return false;
+    }
+    decoded.split('.').any(|label| {
+        let scripts: HashSet<Script> = label
+            .chars()
+            .map(|c| c.script())
+            .filter(|s| !matches!(s, Script::Common | Script::Inherited | Script::Unknown))
+            .collect();
+        scripts.len() > 1
+    })
+}
+
 pub fn format_num(n: usize) -> String {
     let s = n.to_string();
     let mut result = String::with_capacity(s.len() + s.len() / 3);
@@ -126,6 +428,18 @@ pub fn format_num(n: usize) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn entry_key_round_trips() {
+        for entry in [
+            Entry::Exact("a.com".to_string()),
+            Entry::Wildcard("a.com".to_string()),
+        ] {
+            let back = Entry::from_key(&entry.to_key());
+            assert_eq!(back.domain(), "a.com");
+            assert_eq!(back, entry);
+        }
+    }
+
     #[test]
     fn test_validate_domain() {
         assert!(validate_domain("example.com"));
@@ -137,10 +451,52 @@ mod tests {
         assert!(!validate_domain("-invalid.com"));
     }
 
+    #[test]
+    fn test_domain_policy_strict_and_lenient() {
+        let strict = DomainPolicy::strict();
+        assert!(strict.validate("ads.example.com"));
+        assert!(!strict.validate("_dmarc.example.com"));
+        assert!(!strict.validate("ad_server.example.com"));
+
+        let lenient = DomainPolicy::with_extra_chars("_");
+        assert!(lenient.validate("_dmarc.example.com"));
+        assert!(lenient.validate("ad_server.example.com"));
+        assert!(lenient.validate("*.ad_server.example.com"));
+        assert!(!lenient.validate("ad~server.example.com"));
+        assert!(!lenient.validate("-invalid.com"));
+        assert!(!lenient.validate("localhost"));
+    }
+
+    #[test]
+    fn test_parse_allow_chars() {
+        assert_eq!(parse_allow_chars("_~").as_deref(), Ok("_~"));
+        assert!(parse_allow_chars("a").is_err());
+        assert!(parse_allow_chars(" ").is_err());
+        assert!(parse_allow_chars("*").is_err());
+        assert!(parse_allow_chars("_.").is_err());
+    }
+
+    #[test]
+    fn parent_domains_walks_suffixes() {
+        assert_eq!(
+            parent_domains("a.b.example.com").collect::<Vec<_>>(),
+            ["b.example.com", "example.com", "com"]
+        );
+        assert_eq!(parent_domains("localhost").count(), 0);
+    }
+
     #[test]
     fn test_normalize_domain() {
         assert_eq!(normalize_domain("Example.COM"), "example.com");
         assert_eq!(normalize_domain("test.com."), "test.com");
+        assert_eq!(normalize_domain("München-Ads.de"), "xn--mnchen-ads-9db.de");
+        assert_eq!(
+            normalize_domain("xn--mnchen-ads-9db.de"),
+            "xn--mnchen-ads-9db.de"
+        );
+        // Cyrillic `а` in an otherwise Latin name (a homograph)
+        assert_eq!(normalize_domain("p\u{430}ypal.com."), "xn--pypal-4ve.com");
+        assert!(validate_domain(&normalize_domain("p\u{430}ypal.com")));
     }
 
     #[test]
@@ -155,6 +511,155 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_extract_entry_hosts_whitespace_variants() {
+        let expected = Some(Entry::Exact("ads.example.com".to_string()));
+        assert_eq!(extract_entry("0.0.0.0\tads.example.com", false), expected);
+        assert_eq!(
+            extract_entry("127.0.0.1\t\tads.example.com", false),
+            expected
+        );
+        assert_eq!(
+            extract_entry("0.0.0.0 \t  ads.example.com\t", false),
+            expected
+        );
+        assert_eq!(extract_entry("   0.0.0.0 ads.example.com", false), expected);
+        assert_eq!(extract_entry("\t0.0.0.0\tads.example.com", false), expected);
+    }
+
+    #[test]
+    fn test_extract_entry_hosts_trailing_content() {
+        let expected = Some(Entry::Exact("ads.example.com".to_string()));
+        assert_eq!(
+            extract_entry("0.0.0.0\tads.example.com\t# tracker", false),
+            expected
+        );
+        assert_eq!(
+            extract_entry("0.0.0.0 ads.example.com#tracker", false),
+            expected
+        );
+        assert_eq!(
+            extract_entry("0.0.0.0 ads.example.com www.ads.example.com", false),
+            expected
+        );
+        assert_eq!(extract_entry("0.0.0.0\t# only a comment", false), None);
+    }
+
+    #[test]
+    fn test_extract_entries_hosts_aliases() {
+        let exact = |d: &str| Entry::Exact(d.to_string());
+        assert_eq!(
+            extract_entries(
+                "0.0.0.0 ads.example.com tracker.example.com beacon.example.com",
+                false
+            ),
+            [
+                exact("ads.example.com"),
+                exact("tracker.example.com"),
+                exact("beacon.example.com")
+            ]
+        );
+        assert_eq!(
+            extract_entries("127.0.0.1\tlocalhost\tads.example.com # note x.com", false),
+            [exact("ads.example.com")]
+        );
+        assert_eq!(
+            extract_entries("||ads.example.com^", true),
+            [Entry::Wildcard("ads.example.com".to_string())]
+        );
+        assert!(extract_entries("# 0.0.0.0 ads.example.com", false).is_empty());
+    }
+
+    #[test]
+    fn test_extract_entry_hosts_ipv6() {
+        assert_eq!(
+            extract_entry(":: ads.example.com", false),
+            Some(Entry::Exact("ads.example.com".to_string()))
+        );
+        assert_eq!(extract_entry("::1 localhost", false), None);
+
+        let tracker = Some(Entry::Exact("tracker.example.com".to_string()));
+        for line in [
+            "::1 tracker.example.com",
+            "fe80::1 tracker.example.com",
+            "fe80:0000:0000:0000:0000:0000:0000:0001\ttracker.example.com",
+            "2001:db8::8a2e:370:7334  tracker.example.com  # note",
+            "fe80::1%eth0 tracker.example.com",
+            "::ffff:127.0.0.1 tracker.example.com",
+        ] {
+            assert_eq!(extract_entry(line, false), tracker, "{line}");
+        }
+        assert_eq!(
+            hosts_line_ip("fe80::1 tracker.example.com"),
+            Some("fe80::1")
+        );
+
+        // Hex-and-colon runs that aren't addresses are not hosts lines
+        assert_eq!(extract_entry("fe80:::1 tracker.example.com", false), None);
+        assert_eq!(
+            extract_entry("1:2:3:4:5:6:7:8:9 tracker.example.com", false),
+            None
+        );
+        assert_eq!(hosts_line_ip("abc: tracker.example.com"), None);
+
+        // The IPv4 path is unchanged
+        assert_eq!(
+            extract_entry("127.0.0.1 tracker.example.com", false),
+            tracker
+        );
+    }
+
+    #[test]
+    fn test_salvage_hosts_url() {
+        let ads = Some(Entry::Exact("ads.example.com".to_string()));
+        assert_eq!(
+            extract_entry("0.0.0.0 http://ads.example.com/path", false),
+            None
+        );
+        assert_eq!(
+            salvage_hosts_url("0.0.0.0 http://ads.example.com/path"),
+            ads
+        );
+        assert_eq!(
+            salvage_hosts_url("0.0.0.0 https://ADS.example.com?q=1"),
+            ads
+        );
+        assert_eq!(salvage_hosts_url("127.0.0.1 ads.example.com:8080"), ads);
+        assert_eq!(
+            salvage_hosts_url("0.0.0.0 https://user@ads.example.com:443/x"),
+            ads
+        );
+
+        // Nothing to strip, not a hosts line, or no valid host left
+        assert_eq!(salvage_hosts_url("0.0.0.0 ads.example.com"), None);
+        assert_eq!(salvage_hosts_url("http://ads.example.com/path"), None);
+        assert_eq!(salvage_hosts_url("0.0.0.0 http:///path"), None);
+        assert_eq!(salvage_hosts_url("0.0.0.0 ads.example.com:http"), None);
+    }
+
+    #[test]
+    fn test_has_dnstype_modifier() {
+        assert!(has_dnstype_modifier("||example.com^$dnstype=HTTPS"));
+        assert!(has_dnstype_modifier("||example.com^$dnstype=AAAA|HTTPS"));
+        assert!(has_dnstype_modifier("||example.com^$important,dnstype=~A"));
+        assert!(!has_dnstype_modifier("||example.com^"));
+        assert!(!has_dnstype_modifier("||example.com^$important"));
+        assert!(!has_dnstype_modifier("0.0.0.0 example.com"));
+    }
+
+    #[test]
+    fn test_hosts_line_ip() {
+        assert_eq!(hosts_line_ip("0.0.0.0 ads.example.com"), Some("0.0.0.0"));
+        assert_eq!(
+            hosts_line_ip("\t10.1.2.3\tintranet.corp.com"),
+            Some("10.1.2.3")
+        );
+        assert_eq!(hosts_line_ip(":: ads.example.com # v6"), Some("::"));
+        assert_eq!(hosts_line_ip("ads.example.com"), None);
+        assert_eq!(hosts_line_ip("||ads.example.com^"), None);
+        assert_eq!(hosts_line_ip("# 10.1.2.3 intranet.corp.com"), None);
+    }
+
     #[test]
     fn test_extract_entry_abp_respects_flag() {
         assert_eq!(
@@ -171,6 +676,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_extract_entry_dnsrewrite_to_sinkhole_is_kept() {
+        let expected = Some(Entry::Exact("ads.example.com".to_string()));
+        for rule in [
+            "||ads.example.com^$dnsrewrite=NOERROR;A;0.0.0.0",
+            "||ads.example.com^$dnsrewrite=NOERROR;AAAA;::",
+            "||ads.example.com^$dnsrewrite=NXDOMAIN;;",
+            "||ads.example.com^$dnsrewrite=REFUSED",
+            "||ads.example.com^$dnsrewrite=127.0.0.1",
+            "||ads.example.com^$important,dnsrewrite=NOERROR;A;0.0.0.0",
+        ] {
+            assert_eq!(extract_entry(rule, false), expected, "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_extract_entry_dnsrewrite_redirect_is_dropped() {
+        for rule in [
+            "||intranet.example.com^$dnsrewrite=NOERROR;A;10.0.0.5",
+            "||search.example.com^$dnsrewrite=NOERROR;CNAME;safe.example.net",
+            "||search.example.com^$dnsrewrite=safe.example.net",
+            "||intranet.example.com^$dnsrewrite=192.168.1.10",
+            "||intranet.example.com^$dnsrewrite",
+        ] {
+            assert_eq!(extract_entry(rule, true), None, "{rule}");
+        }
+    }
+
     #[test]
     fn test_extract_entry_star_sugar_respects_flag() {
         assert_eq!(
@@ -208,6 +741,51 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pihole_regex() {
+        for line in [
+            "(^|\\.)doubleclick\\.net$",
+            "^ad[sx]?[0-9]*\\.",
+            "^tracker\\.;querytype=A",
+            "  telemetry\\.example\\.com$  ",
+        ] {
+            assert_eq!(pihole_regex(line), Some(line.trim()), "{line}");
+        }
+        for line in [
+            "example.com",
+            "||ads.example.com^",
+            "@@||ok.example.com^",
+            "0.0.0.0 ads.example.com",
+            "# (^|\\.)comment\\.com$",
+            "/banner[0-9]+/",
+            "^(unclosed\\.",
+            "",
+            // Would match every domain
+            "^",
+            "$",
+            "(.*)",
+            ".*$",
+            "^.*\\..*$",
+            "(^|\\.)ads\\.com$|",
+            // No literal label next to the dot
+            "^[a-z]+\\.[0-9]{12}$",
+        ] {
+            assert_eq!(pihole_regex(line), None, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_is_mixed_script() {
+        // "аpple.com" with a Cyrillic "а"
+        assert!(is_mixed_script("xn--pple-43d.com"));
+        // "münchen.de" is all Latin
+        assert!(!is_mixed_script("xn--mnchen-3ya.de"));
+        // "пример.рф" is all Cyrillic
+        assert!(!is_mixed_script("xn--e1afmkfd.xn--p1ai"));
+        assert!(!is_mixed_script("apple.com"));
+        assert!(!is_mixed_script("xn--invalid-.com"));
+    }
+
     #[test]
     fn test_format_num() {
         assert_eq!(format_num(0), "0");
@@ -1,8 +1,15 @@
+mod bench;
+mod blacklist;
+mod bloom;
+mod classify;
 mod client;
 mod config;
 mod domain;
+mod output;
 mod pipeline;
 mod progress;
+#[cfg(test)]
+mod test_util;
 mod whitelist;
 
 use clap::Parser;
@@ -24,6 +31,14 @@ struct Cli {
     #[arg(short, long, default_value = "whitelist.txt")]
     whitelist: String,
 
+    /// Blacklist file of domains to add to every production list, whitelist still applies
+    #[arg(long, value_name = "PATH")]
+    blacklist: Option<String>,
+
+    /// Add blacklist domains only to this category instead of every one
+    #[arg(long, value_name = "CATEGORY", requires = "blacklist")]
+    blacklist_category: Option<String>,
+
     /// Base output directory for individual lists
     #[arg(short, long, default_value = "pihole_blocklists")]
     base_dir: String,
@@ -32,14 +47,50 @@ struct Cli {
     #[arg(short, long, default_value = "pihole_blocklists_prod")]
     prod_dir: String,
 
-    /// Number of concurrent downloads (1-16)
+    /// Write a category's individual and production lists to DIR instead (repeatable)
+    #[arg(long, value_name = "CATEGORY=DIR", value_parser = config::parse_category_dir)]
+    category_dir: Vec<(String, String)>,
+
+    /// Number of concurrent downloads, and worker threads for parsing and whitelist filtering (1-16)
     #[arg(short, long, default_value_t = 4)]
     threads: usize,
 
+    /// Limit requests to each host to RPS per second; other hosts still download in parallel
+    #[arg(long, value_name = "RPS", value_parser = client::parse_rate_limit)]
+    rate_limit: Option<f64>,
+
+    /// Retries for a failed download before giving up (0 = fail on the first error)
+    #[arg(long, value_name = "N", default_value_t = client::DEFAULT_MAX_RETRIES)]
+    max_retries: u32,
+
+    /// Wait before the first retry in milliseconds, doubled for each retry after
+    #[arg(long, value_name = "MS", default_value_t = client::DEFAULT_RETRY_BACKOFF_MS)]
+    retry_backoff_ms: u64,
+
+    /// Download lists in random order, optionally from a fixed SEED for reproducible runs
+    #[arg(long, value_name = "SEED", num_args = 0..=1)]
+    shuffle: Option<Option<u64>>,
+
     /// HTTP request timeout in seconds
     #[arg(long, default_value_t = 30)]
     timeout: u64,
 
+    /// Seconds allowed to establish a connection, separate from --timeout (0 = no separate limit)
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    connect_timeout: u64,
+
+    /// Reject a download with fewer than N domains and keep the previous file (0 = off)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    min_domains: usize,
+
+    /// Exit successfully instead of failing when the config has no valid entries
+    #[arg(long)]
+    allow_empty: bool,
+
+    /// Fail, after listing every offending line, if the config has any malformed line
+    #[arg(long)]
+    strict_config: bool,
+
     /// Skip downloading (use existing local files)
     #[arg(long)]
     skip_download: bool,
@@ -48,26 +99,194 @@ struct Cli {
     #[arg(long)]
     skip_optimize: bool,
 
+    /// Don't keep each list's .txt/.txt.raw files; only the production lists are written
+    #[arg(long, conflicts_with_all = ["skip_download", "skip_optimize", "refresh_one", "per_list_stats"])]
+    no_individual_files: bool,
+
+    /// Download, parse and whitelist as usual, but only print list sizes; no files are written
+    #[arg(long, conflicts_with_all = ["skip_optimize", "refresh_one", "json_errors", "stats_json"])]
+    count_only: bool,
+
+    /// Force re-download of only the named list; production lists are not rebuilt
+    #[arg(long, value_name = "NAME", conflicts_with = "skip_download")]
+    refresh_one: Option<String>,
+
     /// Disable incremental updates (force full re-download)
     #[arg(long)]
     no_incremental: bool,
 
+    /// Reuse the optimized file of any list downloaded less than this long ago
+    /// (e.g. 30m, 6h, 2d) instead of requesting it again
+    #[arg(long, value_name = "DURATION", value_parser = config::parse_duration)]
+    min_refresh_interval: Option<std::time::Duration>,
+
+    /// Save download progress after every list instead of once after all downloads
+    #[arg(long)]
+    progress_sync_each: bool,
+
     /// Dry run mode (show what would happen without doing it)
     #[arg(long)]
     dry_run: bool,
 
+    /// Print each category with its lists and any invalid config lines, then exit
+    #[arg(long)]
+    list_categories: bool,
+
     /// Disable subdomain matching in whitelist
     #[arg(long)]
     no_whitelist_subdomain: bool,
 
+    /// Fail if the whitelist file is missing or unreadable instead of using an empty whitelist
+    #[arg(long)]
+    require_whitelist: bool,
+
+    /// Warn when one whitelist entry's subdomain matching removes more than N domains (0 = off)
+    #[arg(long, value_name = "N", default_value_t = whitelist::DEFAULT_BROAD_ENTRY_THRESHOLD)]
+    whitelist_warn_threshold: usize,
+
     /// Generate detailed whitelist match report
     #[arg(long)]
     whitelist_report: bool,
 
+    /// Print a matrix of shared domains between every pair of categories, and
+    /// write the overlapping pairs, most shared first, to FILE
+    /// [default: PROD_DIR/category_overlap.txt]
+    #[arg(long, alias = "report-overlap", value_name = "FILE", num_args = 0..=1)]
+    overlap_report: Option<Option<String>>,
+
+    /// Reference blocklist whose domains are removed from every production list
+    #[arg(long, value_name = "FILE")]
+    subtract: Option<String>,
+
+    /// Write mixed-script (possible homograph) master domains to homographs.txt
+    #[arg(long)]
+    report_homographs: bool,
+
     /// Categories to also emit as an ABP-style variant (e.g. nsfw) that blocks subdomains
     #[arg(long, value_delimiter = ',')]
     abp_lists: Vec<String>,
 
+    /// Only import hosts entries that point at a sinkhole IP (see --sinkhole-ips)
+    #[arg(long)]
+    sinkhole_ips_only: bool,
+
+    /// Addresses treated as sinkholes by --sinkhole-ips-only
+    #[arg(long, value_delimiter = ',', default_value = "0.0.0.0,127.0.0.1,::")]
+    sinkhole_ips: Vec<String>,
+
+    /// Address(es) to point hosts and dnsmasq output at, cycled per domain [default: 0.0.0.0]
+    #[arg(long, value_name = "IP", value_delimiter = ',')]
+    sinkhole_ip: Vec<std::net::IpAddr>,
+
+    /// Extra characters to accept in domain labels, e.g. `_` (default: letters, digits, hyphen)
+    #[arg(long, value_name = "CHARS", value_parser = domain::parse_allow_chars)]
+    allow_chars: Option<String>,
+
+    /// Drop `*.domain` lines from non-abp sources instead of converting them to the apex domain
+    #[arg(long)]
+    drop_source_wildcards: bool,
+
+    /// Treat ABP rules scoped with $dnstype= as whole-domain blocks instead of skipping them
+    #[arg(long)]
+    keep_dnstype_rules: bool,
+
+    /// Recover the host from hosts lines written as URLs or host:port instead of dropping them
+    #[arg(long)]
+    salvage_host_urls: bool,
+
+    /// Sort domains from `auto` lists into advertising/tracking/malicious by keywords in their names
+    #[arg(long)]
+    auto_categorize: bool,
+
+    /// Write every configured category file even when it has no domains
+    #[arg(long)]
+    write_empty_lists: bool,
+
+    /// Write a JSON array describing every list that failed to download or load
+    #[arg(long, value_name = "FILE")]
+    json_errors: Option<String>,
+
+    /// Format of the production lists: Pi-hole hosts lines, an RPZ zone, dnsmasq rules, bare
+    /// domains or an AdGuard filter list
+    #[arg(long, value_enum, default_value_t = output::OutputFormat::Hosts)]
+    format: output::OutputFormat,
+
+    /// Write the run summary counters to this path as JSON
+    #[arg(long, value_name = "PATH")]
+    stats_json: Option<String>,
+
+    /// Line ending for written list and report files
+    #[arg(long, value_enum, default_value_t = output::LineEnding::Lf)]
+    line_ending: output::LineEnding,
+
+    /// Order categories are processed and logged in
+    #[arg(long, value_enum, default_value_t = config::CategoryOrder::Config)]
+    category_order: config::CategoryOrder,
+
+    /// URL to POST a JSON run summary to when the master list changes
+    #[arg(long)]
+    webhook_url: Option<String>,
+
+    /// Minimum number of added plus removed master domains that triggers the webhook
+    #[arg(long, default_value_t = 1000)]
+    webhook_threshold: usize,
+
+    /// Write sources.txt mapping each master domain to the first list that contributed it
+    #[arg(long)]
+    annotate_sources: bool,
+
+    /// Keep the previous master list as all_domains.txt.bak before overwriting it
+    #[arg(long)]
+    backup_master: bool,
+
+    /// Write {name}.stats.json next to each downloaded list
+    #[arg(long)]
+    per_list_stats: bool,
+
+    /// List the names of failed and skipped lists under the summary
+    #[arg(long)]
+    summary_lists: bool,
+
+    /// Show the N lists contributing the most domains after the summary
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    top_lists: usize,
+
+    /// Show each downloaded list's previous and new domain count, flagging sharp drops
+    #[arg(long)]
+    diff: bool,
+
+    /// Drop entries whose parent domain is also listed (off by default: Pi-hole matches exact names)
+    #[arg(long)]
+    collapse_subdomains: bool,
+
+    /// Write the domains the whitelist removed to this path as a plain allowlist
+    #[arg(long, value_name = "PATH")]
+    export_allowlist: Option<String>,
+
+    /// Write every category's domains to this path as one file, each tagged `# [category]`
+    #[arg(long, value_name = "PATH")]
+    combined_annotated: Option<String>,
+
+    /// Write a JSON map of each whitelist rule to the domains it removed to this path
+    #[arg(long, value_name = "PATH")]
+    whitelist_audit_json: Option<String>,
+
+    /// Write a Bloom filter of the final master domains to this path
+    #[arg(long, value_name = "PATH")]
+    export_bloom: Option<String>,
+
+    /// Target false-positive rate for --export-bloom (0 < rate < 1)
+    #[arg(long, value_name = "RATE", default_value_t = 0.01, value_parser = bloom::parse_fp_rate)]
+    bloom_fp_rate: f64,
+
+    /// Normalize a local list file into OUTPUT (sorted, deduplicated), then exit
+    #[arg(long, value_names = ["INPUT", "OUTPUT"], num_args = 2)]
+    normalize_only: Vec<String>,
+
+    /// Benchmark parsing, dedup, whitelist filtering and writing over local files, then exit
+    #[arg(long, value_name = "FILE", num_args = 1..)]
+    bench: Vec<String>,
+
     /// Verbose logging (debug level)
     #[arg(short, long)]
     verbose: bool,
@@ -103,22 +322,116 @@ async fn main() {
         })
         .init();
 
+    // `--threads` also sizes the pool for CPU-bound work, so `-t 1` runs it serially
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.threads.clamp(1, 16))
+        .build_global()
+    {
+        log::warn!("Failed to set worker thread count: {e}");
+    }
+
+    if let Some(chars) = &cli.allow_chars {
+        domain::set_domain_policy(domain::DomainPolicy::with_extra_chars(chars));
+    }
+
+    if let [input, output] = cli.normalize_only.as_slice() {
+        match pipeline::normalize_file(input, output, cli.line_ending) {
+            Ok(count) => log::info!("Wrote {count} normalized domains to {output}"),
+            Err(e) => {
+                log::error!("{e:#}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if cli.list_categories {
+        if let Err(e) = config::print_categories(&cli.config, cli.category_order) {
+            log::error!("{e:#}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if !cli.bench.is_empty() {
+        let whitelist =
+            whitelist::WhitelistManager::load(&cli.whitelist, !cli.no_whitelist_subdomain);
+        if let Err(e) = bench::run_bench(&cli.bench, &whitelist) {
+            log::error!("{e:#}");
+            process::exit(1);
+        }
+        return;
+    }
+
     let config = AppConfig {
         config_file: cli.config,
         whitelist_file: cli.whitelist,
+        blacklist_file: cli.blacklist,
+        blacklist_category: cli
+            .blacklist_category
+            .as_deref()
+            .map(config::normalize_category),
         base_dir: cli.base_dir,
         prod_dir: cli.prod_dir,
         threads: cli.threads.clamp(1, 16),
         timeout: if cli.timeout == 0 { 30 } else { cli.timeout },
+        connect_timeout: (cli.connect_timeout > 0).then_some(cli.connect_timeout),
+        shuffle: cli.shuffle.is_some(),
+        shuffle_seed: cli.shuffle.flatten(),
         skip_download: cli.skip_download,
         skip_optimize: cli.skip_optimize,
+        no_individual_files: cli.no_individual_files,
+        refresh_one: cli.refresh_one,
+        count_only: cli.count_only,
+        min_domains: cli.min_domains,
+        progress_sync_each: cli.progress_sync_each,
         incremental: !cli.no_incremental,
+        min_refresh_interval: cli.min_refresh_interval,
         dry_run: cli.dry_run,
         quiet: cli.quiet,
         verbose: cli.verbose,
         whitelist_subdomain: !cli.no_whitelist_subdomain,
+        require_whitelist: cli.require_whitelist,
         whitelist_report: cli.whitelist_report,
+        whitelist_warn_threshold: cli.whitelist_warn_threshold,
         abp_lists: cli.abp_lists,
+        write_empty_lists: cli.write_empty_lists,
+        line_ending: cli.line_ending,
+        output_format: cli.format,
+        category_order: cli.category_order,
+        webhook_url: cli.webhook_url,
+        webhook_threshold: cli.webhook_threshold,
+        annotate_sources: cli.annotate_sources,
+        backup_master: cli.backup_master,
+        per_list_stats: cli.per_list_stats,
+        top_lists: cli.top_lists,
+        count_diff: cli.diff,
+        collapse_subdomains: cli.collapse_subdomains,
+        rate_limit: cli.rate_limit,
+        max_retries: cli.max_retries,
+        retry_backoff_ms: cli.retry_backoff_ms,
+        summary_lists: cli.summary_lists,
+        sinkhole_ips_only: cli.sinkhole_ips_only,
+        sinkhole_ips: cli.sinkhole_ips,
+        hosts_ips: cli.sinkhole_ip,
+        drop_source_wildcards: cli.drop_source_wildcards,
+        keep_dnstype_rules: cli.keep_dnstype_rules,
+        auto_categorize: cli.auto_categorize,
+        salvage_host_urls: cli.salvage_host_urls,
+        overlap_report: cli.overlap_report.is_some(),
+        overlap_file: cli.overlap_report.flatten(),
+        export_bloom: cli.export_bloom,
+        export_allowlist: cli.export_allowlist,
+        whitelist_audit_json: cli.whitelist_audit_json,
+        combined_annotated: cli.combined_annotated,
+        subtract: cli.subtract,
+        report_homographs: cli.report_homographs,
+        json_errors: cli.json_errors,
+        stats_json: cli.stats_json,
+        allow_empty: cli.allow_empty,
+        strict_config: cli.strict_config,
+        category_dirs: cli.category_dir.into_iter().collect(),
+        bloom_fp_rate: cli.bloom_fp_rate,
     };
 
     if !config.quiet {
@@ -141,6 +454,8 @@ async fn main() {
     };
 
     if let Err(e) = manager.run().await {
+        // `process::exit` skips destructors; drop here so pending progress is saved
+        drop(manager);
         log::error!("{e:#}");
         process::exit(1);
     }
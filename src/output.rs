@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Newline style for generated list and report files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+/// Syntax of written blocklist files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// `0.0.0.0 domain` hosts lines (`||domain^` for wildcard entries).
+    #[default]
+    Hosts,
+    /// A BIND Response Policy Zone answering NXDOMAIN for each domain and its
+    /// subdomains.
+    Rpz,
+    /// dnsmasq `address=/domain/ip` rules, each covering a domain and its
+    /// subdomains.
+    Dnsmasq,
+    /// Bare domains, one per line, for AdGuard Home, NextDNS, Blocky and the like.
+    Domains,
+    /// An AdGuard filter list: `! Title:` metadata header and `||domain^` rules.
+    Adguard,
+    /// `||domain^` for every entry; used for the `--abp-lists` variants.
+    #[value(skip)]
+    Abp,
+}
+
+/// Writer that emits every `\n` as the configured line ending, so callers can
+/// keep using `writeln!`.
+pub struct EolWriter<W: Write> {
+    inner: W,
+    line_ending: LineEnding,
+}
+
+impl<W: Write> EolWriter<W> {
+    pub fn new(inner: W, line_ending: LineEnding) -> Self {
+        Self { inner, line_ending }
+    }
+}
+
+impl<W: Write> Write for EolWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.line_ending == LineEnding::Lf {
+            return self.inner.write(buf);
+        }
+        for (i, segment) in buf.split(|&b| b == b'\n').enumerate() {
+            if i > 0 {
+                self.inner.write_all(b"\r\n")?;
+            }
+            self.inner.write_all(segment)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Create (truncating) a buffered output file using `line_ending`.
+pub fn create_output(path: &Path, line_ending: LineEnding) -> Result<EolWriter<BufWriter<File>>> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(EolWriter::new(BufWriter::new(file), line_ending))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(line_ending: LineEnding) -> Vec<u8> {
+        let mut w = EolWriter::new(Vec::new(), line_ending);
+        writeln!(w, "# header").unwrap();
+        writeln!(w).unwrap();
+        write!(w, "0.0.0.0 a.com\n0.0.0.0 b.com\n").unwrap();
+        w.inner
+    }
+
+    #[test]
+    fn lf_is_passed_through() {
+        assert_eq!(
+            render(LineEnding::Lf),
+            b"# header\n\n0.0.0.0 a.com\n0.0.0.0 b.com\n"
+        );
+    }
+
+    #[test]
+    fn crlf_replaces_every_newline() {
+        assert_eq!(
+            render(LineEnding::Crlf),
+            b"# header\r\n\r\n0.0.0.0 a.com\r\n0.0.0.0 b.com\r\n"
+        );
+    }
+}
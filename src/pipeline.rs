@@ -1,44 +1,339 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
 use futures::stream::{self, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{debug, error, info, warn};
-use std::collections::{HashMap, HashSet};
-use std::io::Write;
-use std::path::Path;
-use std::time::Instant;
-
-use crate::client::HttpClient;
-use crate::config::{load_blocklists, AppConfig};
-use crate::domain::{extract_entry, format_num};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::blacklist::BlacklistManager;
+use crate::bloom::BloomFilter;
+use crate::classify::{classify, CATEGORIES};
+use crate::client::{Body, DownloadResult, HttpClient, SpooledBody};
+use crate::config::{load_blocklists, ordered_categories, AppConfig, Blocklist, CategoryOrder};
+use crate::domain::{
+    extract_entries, format_num, has_dnstype_modifier, hosts_line_ip, is_mixed_script,
+    parent_domains, pihole_regex, salvage_hosts_url, Entry,
+};
+use crate::output::{create_output, LineEnding, OutputFormat};
 use crate::progress::ProgressTracker;
-use crate::whitelist::WhitelistManager;
+use crate::whitelist::{export_allowlist, WhitelistManager, WhitelistMatches};
+
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub total_lists: usize,
+    pub successful: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Lists marked disabled in the config, not counted in `total_lists`.
+    pub disabled: usize,
+    pub listed_domains: usize,
+    pub unique_domains: usize,
+    pub whitelisted: usize,
+    /// `whitelisted` by the kind of entry that matched.
+    pub whitelist_matches: WhitelistMatches,
+    pub final_domains: usize,
+    pub collapsed_subdomains: usize,
+    pub regex_entries: usize,
+    pub runtime_seconds: f64,
+}
+
+impl RunSummary {
+    fn print(&self) {
+        println!();
+        println!("{}", "=".repeat(60));
+        println!("{:>35}", "SUMMARY");
+        println!("{}", "=".repeat(60));
+        println!("Total lists:        {}", self.total_lists);
+        println!("Successful:         {}", self.successful);
+        println!("Skipped:            {}", self.skipped);
+        println!("Failed:             {}", self.failed);
+        if self.disabled > 0 {
+            println!("Disabled:           {}", self.disabled);
+        }
+        if self.listed_domains > self.unique_domains {
+            let duplicates = self.listed_domains - self.unique_domains;
+            println!("Listed domains:     {}", format_num(self.listed_domains));
+            println!(
+                "Duplicates:         {} ({:.1}%)",
+                format_num(duplicates),
+                duplicates as f64 * 100.0 / self.listed_domains as f64
+            );
+        }
+        println!("Unique domains:     {}", format_num(self.unique_domains));
+        if self.whitelisted > 0 {
+            println!("Whitelisted:        {}", format_num(self.whitelisted));
+            let matches = &self.whitelist_matches;
+            println!("  Exact:            {}", format_num(matches.exact));
+            println!("  Subdomain:        {}", format_num(matches.subdomain));
+            println!("  Pattern:          {}", format_num(matches.pattern));
+            println!("Final count:        {}", format_num(self.final_domains));
+        }
+        if self.collapsed_subdomains > 0 {
+            println!(
+                "Collapsed:          {}",
+                format_num(self.collapsed_subdomains)
+            );
+        }
+        if self.regex_entries > 0 {
+            println!("Regex entries:      {}", format_num(self.regex_entries));
+        }
+        println!("Runtime:            {:.2} seconds", self.runtime_seconds);
+        println!("{}", "=".repeat(60));
+        println!();
+    }
+}
+
+/// A list that could not be downloaded or loaded, for `--json-errors`.
+#[derive(Debug, Serialize)]
+struct ListFailure {
+    name: String,
+    url: String,
+    category: String,
+    status: Option<u16>,
+    error: String,
+    retries: u32,
+}
+
+impl ListFailure {
+    fn new(bl: &Blocklist, status: Option<u16>, error: String, retries: u32) -> Self {
+        Self {
+            name: bl.name.clone(),
+            url: bl.display_url.clone(),
+            category: bl.category.clone(),
+            status,
+            error,
+            retries,
+        }
+    }
+}
+
+/// What `run` gathers from every list, downloaded or loaded from disk.
+struct ListResults {
+    category_domains: HashMap<String, HashSet<String>>,
+    /// First list each master domain came from, for `--annotate-sources`.
+    domain_sources: Option<SourceMap>,
+    regexes: HashSet<String>,
+    /// Sum of per-list (non-NSFW) domain counts, before cross-list dedup
+    listed_domains: usize,
+    auto_counts: Option<HashMap<&'static str, usize>>,
+    successful: usize,
+    failures: Vec<ListFailure>,
+    /// Lists that were not modified and reused their previous file.
+    skipped_names: Vec<String>,
+    /// Previous vs new domain count of each downloaded list, for --diff
+    count_changes: Vec<CountChange>,
+    /// (duplicate list, first list with the same content)
+    content_duplicates: Vec<(String, String)>,
+}
+
+impl ListResults {
+    fn new(annotate_sources: bool, auto_categorize: bool) -> Self {
+        Self {
+            category_domains: HashMap::new(),
+            domain_sources: annotate_sources.then(HashMap::new),
+            regexes: HashSet::new(),
+            listed_domains: 0,
+            auto_counts: auto_categorize.then(HashMap::new),
+            successful: 0,
+            failures: Vec::new(),
+            skipped_names: Vec::new(),
+            count_changes: Vec::new(),
+            content_duplicates: Vec::new(),
+        }
+    }
+
+    /// Add one list's domains and regexes; `order` is its config position.
+    fn add_list(
+        &mut self,
+        order: usize,
+        bl: &Blocklist,
+        domains: HashSet<String>,
+        regexes: HashSet<String>,
+    ) {
+        record_sources(&mut self.domain_sources, order, bl, &domains);
+        add_list_domains(
+            &mut self.category_domains,
+            &mut self.listed_domains,
+            &mut self.auto_counts,
+            bl,
+            domains,
+        );
+        record_regexes(&mut self.regexes, bl, regexes);
+    }
+}
+
+/// Per-run state `process_download` needs besides the manager itself.
+struct DownloadContext<'a> {
+    filter: ContentFilter<'a>,
+    cache: ContentCache,
+    /// Every configured list, to find the first one that served the same content.
+    blocklists: &'a [Blocklist],
+}
+
+/// Address hosts-format lines point at unless `--sinkhole-ip` is given.
+const DEFAULT_SINKHOLE_IP: &str = "0.0.0.0";
+
+/// Per-list details written to `{name}.stats.json` by `--per-list-stats`.
+#[derive(Debug, Serialize)]
+struct ListStats<'a> {
+    name: &'a str,
+    category: &'a str,
+    url: &'a str,
+    downloaded_at: String,
+    etag: Option<&'a str>,
+    last_modified: Option<&'a str>,
+    bytes: usize,
+    domains: usize,
+    regexes: usize,
+    rejected_lines: usize,
+}
+
+/// The combined master list before and after filtering.
+struct MasterBuild {
+    /// Non-NSFW domains after `--subtract`, before the whitelist.
+    all_domains: HashSet<String>,
+    filtered: HashSet<String>,
+    /// Domains removed by the whitelist.
+    removed: WhitelistMatches,
+    /// `--subtract` reference domains, if any.
+    reference: Option<HashSet<String>>,
+}
+
+/// Domains added to and removed from the master list compared to the previous run.
+#[derive(Debug, Clone, Copy)]
+struct MasterChange {
+    added: usize,
+    removed: usize,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    summary: &'a RunSummary,
+    added: usize,
+    removed: usize,
+}
 
 pub struct BlocklistManager {
     pub config: AppConfig,
     http_client: HttpClient,
     progress: ProgressTracker,
     whitelist: WhitelistManager,
+    blacklist: Option<BlacklistManager>,
 }
 
 impl BlocklistManager {
     pub fn new(config: AppConfig) -> Result<Self> {
-        let http_client = HttpClient::new(config.timeout)?;
-        let progress = ProgressTracker::load();
-        let whitelist = WhitelistManager::load(&config.whitelist_file, config.whitelist_subdomain);
+        let http_client = HttpClient::new(config.timeout, config.connect_timeout)?
+            .with_rate_limit(config.rate_limit)
+            .with_retries(config.max_retries, config.retry_backoff_ms);
+        let progress = ProgressTracker::load().with_sync_each(config.progress_sync_each);
+        let whitelist = if config.require_whitelist {
+            WhitelistManager::load_required(&config.whitelist_file, config.whitelist_subdomain)?
+        } else {
+            WhitelistManager::load(&config.whitelist_file, config.whitelist_subdomain)
+        }
+        .with_broad_entry_threshold(config.whitelist_warn_threshold);
+        let blacklist = config
+            .blacklist_file
+            .as_deref()
+            .map(BlacklistManager::load)
+            .transpose()?;
 
         Ok(Self {
             config,
             http_client,
             progress,
             whitelist,
+            blacklist,
         })
     }
 
     pub async fn run(&mut self) -> Result<()> {
         let start = Instant::now();
 
-        let blocklists = load_blocklists(&self.config.config_file, &self.progress)?;
-        let categories: HashSet<String> = blocklists.iter().map(|b| b.category.clone()).collect();
+        let mut blocklists = load_blocklists(
+            &self.config.config_file,
+            &self.progress,
+            self.config.allow_empty,
+            self.config.strict_config,
+        )?;
+        if blocklists.is_empty() {
+            return Ok(());
+        }
+        if let Some(name) = &self.config.refresh_one {
+            blocklists.retain(|bl| &bl.name == name);
+            let Some(bl) = blocklists.first_mut() else {
+                bail!("No blocklist named '{name}' in configuration file");
+            };
+            if !bl.enabled {
+                bail!("Blocklist '{name}' is disabled in the configuration file");
+            }
+            bl.etag = None;
+            bl.last_modified = None;
+            info!("Refreshing only '{name}'");
+        }
+        let mut disabled = 0usize;
+        blocklists.retain(|bl| {
+            if !bl.enabled {
+                info!("  {}: disabled, not downloaded", bl.name);
+                disabled += 1;
+            }
+            bl.enabled
+        });
+        if blocklists.is_empty() {
+            if self.config.allow_empty {
+                info!("Every blocklist is disabled, nothing to do");
+                return Ok(());
+            }
+            bail!("Every blocklist in the configuration file is disabled");
+        }
+        // A 304 for a list whose local file is gone would leave it empty, so
+        // drop the validators and fetch it in full instead. Without individual
+        // files there is never a local file, so every list is fetched in full.
+        for bl in &mut blocklists {
+            if self.config.no_individual_files {
+                bl.etag = None;
+                bl.last_modified = None;
+                continue;
+            }
+            let path = self.local_list_path(bl);
+            if clear_validators_if_missing(bl, &path) {
+                warn!(
+                    "  {}: local file {} is missing, forcing a full download",
+                    bl.name,
+                    path.display()
+                );
+            }
+        }
+        let mut categories = ordered_categories(&blocklists, self.config.category_order);
+        if self.config.auto_categorize && blocklists.iter().any(|bl| bl.auto_categorize) {
+            for cat in CATEGORIES {
+                if !categories.iter().any(|c| c == cat) {
+                    categories.push(cat.to_string());
+                }
+            }
+            if self.config.category_order == CategoryOrder::Alpha {
+                categories.sort();
+            }
+        }
+        // --refresh-one keeps one list and writes no production lists
+        if let (Some(category), None) = (&self.config.blacklist_category, &self.config.refresh_one)
+        {
+            check_category(category, &categories)?;
+        }
         let total_lists = blocklists.len();
 
         if self.config.dry_run {
@@ -46,342 +341,2116 @@ impl BlocklistManager {
                 "[DRY RUN] Would process {total_lists} blocklists in {} categories",
                 categories.len()
             );
+            let invalid = self.whitelist.invalid_entries();
+            if invalid > 0 {
+                bail!("[DRY RUN] Whitelist has {invalid} invalid regex/wildcard entries");
+            }
             return Ok(());
         }
 
-        self.create_directories(&categories)?;
+        if !self.config.count_only {
+            self.create_directories(&categories)?;
+        }
 
-        let mut category_domains: HashMap<String, HashSet<String>> = HashMap::new();
-        let mut successful = 0usize;
-        let mut skipped = 0usize;
-        let mut failed = 0usize;
+        let mut results =
+            ListResults::new(self.config.annotate_sources, self.config.auto_categorize);
 
         if self.config.skip_download {
             info!("Skipping downloads, loading existing files...");
-            for bl in &blocklists {
-                let path = Path::new(&self.config.base_dir)
-                    .join(&bl.category)
+            for (order, bl) in blocklists.iter().enumerate() {
+                let path = self
+                    .config
+                    .list_dir(&bl.category)
                     .join(format!("{}.txt", bl.name));
                 if path.exists() {
                     match load_domains_from_file(&path, bl.allow_wildcards) {
                         Ok(domains) => {
                             debug!("  {}: {} domains (from file)", bl.name, domains.len());
-                            category_domains
-                                .entry(bl.category.clone())
-                                .or_default()
-                                .extend(domains);
-                            successful += 1;
+                            results.add_list(order, bl, domains, self.load_local_regexes(bl));
+                            results.successful += 1;
                         }
                         Err(e) => {
                             warn!("  {}: Failed to load - {e}", bl.name);
-                            failed += 1;
+                            results
+                                .failures
+                                .push(ListFailure::new(bl, None, format!("{e:#}"), 0));
                         }
                     }
                 } else {
                     warn!("  {}: No local file found", bl.name);
-                    failed += 1;
+                    results.failures.push(ListFailure::new(
+                        bl,
+                        None,
+                        "No local file found".to_string(),
+                        0,
+                    ));
                 }
             }
         } else {
+            self.download_all(&blocklists, &mut results).await;
+        }
+
+        self.finish(&blocklists, &categories, results, disabled, start)
+            .await
+    }
+
+    /// Download every list, `--threads` at a time, adding each to `results`
+    /// as it completes.
+    async fn download_all(&mut self, blocklists: &[Blocklist], results: &mut ListResults) {
+        let total_lists = blocklists.len();
+        info!(
+            "Downloading {total_lists} blocklists with {} threads...",
+            self.config.threads
+        );
+
+        let pb = if self.config.quiet || self.config.verbose {
+            ProgressBar::hidden()
+        } else {
+            let pb = ProgressBar::new(total_lists as u64);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+                    )
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            pb
+        };
+
+        let client = self.http_client.clone();
+        let incremental = self.config.incremental;
+        // Owned, so the filter doesn't hold `self` borrowed while lists are processed
+        let sinkhole_ips = self.config.sinkhole_ips.clone();
+        let mut ctx = DownloadContext {
+            filter: ContentFilter {
+                sinkhole_ips: self
+                    .config
+                    .sinkhole_ips_only
+                    .then(|| sinkhole_ips.iter().map(String::as_str).collect()),
+                drop_star_wildcards: self.config.drop_source_wildcards,
+                keep_dnstype_rules: self.config.keep_dnstype_rules,
+                salvage_host_urls: self.config.salvage_host_urls,
+            },
+            cache: ContentCache::default(),
+            blocklists,
+        };
+
+        // Lists downloaded within --min-refresh-interval are handled like a
+        // 304: no request, and their optimized file is reused.
+        let fresh: HashSet<String> = match self.config.min_refresh_interval {
+            Some(interval) if incremental && self.config.refresh_one.is_none() => {
+                let now = chrono::Utc::now();
+                blocklists
+                    .iter()
+                    .filter(|bl| {
+                        self.progress.get(&bl.name).is_some_and(|entry| {
+                            downloaded_within(&entry.last_download, interval, now)
+                        }) && self.local_list_path(bl).exists()
+                    })
+                    .map(|bl| bl.name.clone())
+                    .collect()
+            }
+            _ => HashSet::new(),
+        };
+        if !fresh.is_empty() {
             info!(
-                "Downloading {total_lists} blocklists with {} threads...",
-                self.config.threads
+                "Reusing {} lists downloaded within --min-refresh-interval",
+                fresh.len()
             );
+        }
+        let fresh = &fresh;
 
-            let pb = if self.config.quiet || self.config.verbose {
-                ProgressBar::hidden()
-            } else {
-                let pb = ProgressBar::new(total_lists as u64);
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template(
-                            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+        // `order` stays the config position so source attribution doesn't
+        // depend on download order. Large bodies are spooled next to the
+        // list's raw file, except under --count-only which writes nothing.
+        let mut queue: Vec<(usize, Blocklist, Option<PathBuf>)> = blocklists
+            .iter()
+            .enumerate()
+            .map(|(order, bl)| {
+                let spool = (!self.config.count_only).then(|| {
+                    self.config
+                        .list_dir(&bl.category)
+                        .join(format!("{}.txt.raw.part", bl.name))
+                });
+                (order, bl.clone(), spool)
+            })
+            .collect();
+        if self.config.shuffle {
+            shuffle_queue(&mut queue, self.config.shuffle_seed);
+        }
+
+        // Process each download as it completes so only `threads` bodies are
+        // held in memory at once, rather than collecting every result first.
+        let mut downloads = stream::iter(queue)
+            .map(|(order, bl, spool)| {
+                let client = client.clone();
+                async move {
+                    if fresh.contains(&bl.name) {
+                        let result = Ok(DownloadResult {
+                            content: None,
+                            etag: bl.etag.clone(),
+                            last_modified: bl.last_modified.clone(),
+                            content_type: None,
+                            was_modified: false,
+                        });
+                        return (order, bl, result);
+                    }
+                    let result = client
+                        .download(
+                            &bl.url,
+                            &bl.request,
+                            if incremental {
+                                bl.etag.as_deref()
+                            } else {
+                                None
+                            },
+                            if incremental {
+                                bl.last_modified.as_deref()
+                            } else {
+                                None
+                            },
+                            spool.as_deref(),
                         )
-                        .unwrap()
-                        .progress_chars("#>-"),
-                );
-                pb
-            };
+                        .await;
+                    (order, bl, result)
+                }
+            })
+            .buffer_unordered(self.config.threads);
+
+        while let Some((order, bl, result)) = downloads.next().await {
+            pb.inc(1);
 
-            let client = self.http_client.clone();
-            let incremental = self.config.incremental;
-
-            let results: Vec<_> = stream::iter(blocklists.clone())
-                .map(|bl| {
-                    let client = client.clone();
-                    async move {
-                        let result = client
-                            .download(
-                                &bl.url,
-                                if incremental {
-                                    bl.etag.as_deref()
-                                } else {
-                                    None
-                                },
-                                if incremental {
-                                    bl.last_modified.as_deref()
-                                } else {
-                                    None
-                                },
-                            )
-                            .await;
-                        (bl, result)
+            match result {
+                Err(e) => {
+                    error!("  {}: {e}", bl.name);
+                    results
+                        .failures
+                        .push(ListFailure::new(&bl, e.status, e.message, e.retries));
+                }
+                Ok(dl) if !dl.was_modified => {
+                    if fresh.contains(&bl.name) {
+                        debug!("  {}: Downloaded recently (skipped)", bl.name);
+                    } else {
+                        debug!("  {}: Not modified (skipped)", bl.name);
                     }
-                })
-                .buffer_unordered(self.config.threads)
-                .collect()
-                .await;
-
-            for (bl, result) in results {
-                pb.inc(1);
-
-                match result {
-                    Err(e) => {
-                        error!("  {}: {e}", bl.name);
-                        failed += 1;
+                    results.skipped_names.push(bl.name.clone());
+
+                    // Load existing local file for production list generation
+                    if !self.add_local_list(results, order, &bl) {
+                        warn!(
+                            "  {}: not modified but no local file was loaded; \
+                             it will be re-downloaded next run",
+                            bl.name
+                        );
                     }
-                    Ok(dl) if !dl.was_modified => {
-                        debug!("  {}: Not modified (skipped)", bl.name);
-                        skipped += 1;
-
-                        // Load existing local file for production list generation
-                        let path = Path::new(&self.config.base_dir)
-                            .join(&bl.category)
-                            .join(format!("{}.txt", bl.name));
-                        if path.exists() {
-                            if let Ok(domains) = load_domains_from_file(&path, bl.allow_wildcards) {
-                                category_domains
-                                    .entry(bl.category.clone())
-                                    .or_default()
-                                    .extend(domains);
-                            }
-                        }
+                }
+                Ok(dl) => self.process_download(results, &mut ctx, order, &bl, dl),
+            }
+        }
+
+        pb.finish_and_clear();
+        self.progress.flush();
+    }
+
+    /// Parse a freshly downloaded list, save its files and add it to `results`,
+    /// or record it as failed and fall back to the previous file.
+    fn process_download(
+        &mut self,
+        results: &mut ListResults,
+        ctx: &mut DownloadContext,
+        order: usize,
+        bl: &Blocklist,
+        dl: DownloadResult,
+    ) {
+        let body = dl.content.expect("modified response must have content");
+
+        // A large plain-text list stays in its spool file and is read line by
+        // line; `content` then holds only its first bytes, for sniffing.
+        // Anything else is decoded in memory.
+        let (content, spooled, scan) = match open_body(bl, body) {
+            Ok((head, Some(spooled))) => {
+                let scan = scan_spooled(spooled.path());
+                (head, Some(spooled), Some(scan))
+            }
+            Ok((content, None)) => (content, None, None),
+            Err(e) => (Vec::new(), None, Some(Err(e))),
+        };
+        let (content_hash, spooled_invalid, read_error) = match scan {
+            Some(Ok((hash, invalid))) => (hash, invalid, None),
+            Some(Err(e)) => (
+                String::new(),
+                0,
+                Some(format!("Failed to read spooled download: {e}")),
+            ),
+            None => (sha256_hex(&content), 0, None),
+        };
+
+        // A body identical to the last one processed (for servers without
+        // validators) reuses the optimized file, like a 304
+        let unchanged = self.config.incremental
+            && !content_hash.is_empty()
+            && bl
+                .sha256
+                .as_ref()
+                .map_or(true, |expected| *expected == content_hash)
+            && self
+                .progress
+                .get(&bl.name)
+                .and_then(|p| p.content_hash.as_deref())
+                == Some(content_hash.as_str());
+        if unchanged && self.add_local_list(results, order, bl) {
+            debug!("  {}: Content unchanged (skipped)", bl.name);
+            if !self.config.count_only {
+                self.progress.set_validators(
+                    &bl.name,
+                    dl.etag.as_deref(),
+                    dl.last_modified.as_deref(),
+                );
+            }
+            results.skipped_names.push(bl.name.clone());
+            return;
+        }
+        // Lists published as `.gz` files arrive still compressed
+        let (content, gzip_error) = match gunzip_if_compressed(content) {
+            Ok(content) => (content, None),
+            Err(e) => (Vec::new(), Some(format!("{e:#}"))),
+        };
+        let invalid = if spooled.is_some() {
+            spooled_invalid
+        } else {
+            count_invalid_utf8(&content)
+        };
+        if invalid > 0 {
+            warn!(
+                "  {}: Invalid UTF-8 in download, {invalid} replacement characters introduced",
+                bl.name
+            );
+        }
+        let lines = list_lines(bl, &content);
+        let content_key = ContentCache::key(content_hash.clone(), bl);
+        // Same content as an earlier list: read back its optimized file rather
+        // than parsing the body again
+        let reused = ctx
+            .cache
+            .first(&content_key)
+            .and_then(|first| ctx.blocklists.iter().find(|b| b.name == first))
+            .and_then(|first| {
+                let domains = self.load_local_list(first)?;
+                Some((first.name.clone(), domains, self.load_local_regexes(first)))
+            });
+        let (duplicate_of, domains, list_regexes) = match reused {
+            Some((first, domains, regexes)) => (Some(first), domains, regexes),
+            None => {
+                let (domains, regexes) = match &spooled {
+                    Some(spooled) => {
+                        process_spooled(spooled.path(), bl.allow_wildcards, &ctx.filter)
+                            .unwrap_or_else(|e| {
+                                warn!("  {}: Failed to read spooled download: {e}", bl.name);
+                                Default::default()
+                            })
                     }
-                    Ok(dl) => {
-                        let content = dl.content.expect("modified response must have content");
-                        let domains = process_content(&content, bl.allow_wildcards);
-                        let count = domains.len();
+                    None => (
+                        process_content(&lines, bl.allow_wildcards, &ctx.filter),
+                        extract_regexes(&lines),
+                    ),
+                };
+                (None, domains, regexes)
+            }
+        };
+        let count = domains.len();
+        let regex_count = list_regexes.len();
 
-                        if count == 0 {
-                            warn!("  {}: No valid domains extracted", bl.name);
-                        }
+        let rejection = match &bl.sha256 {
+            _ if read_error.is_some() => read_error,
+            _ if gzip_error.is_some() => gzip_error,
+            Some(expected) if *expected != content_hash => Some(format!(
+                "SHA-256 mismatch: expected {expected}, got {content_hash}"
+            )),
+            _ if looks_like_html(dl.content_type.as_deref(), &content) => {
+                Some("Served an HTML page instead of a list".to_string())
+            }
+            _ if count < self.config.min_domains => Some(format!(
+                "Only {count} domains, below --min-domains {}",
+                self.config.min_domains
+            )),
+            _ => None,
+        };
+        if let Some(reason) = rejection {
+            error!(
+                "  {}: {reason}; rejecting download and keeping the previous file",
+                bl.name
+            );
+            results.failures.push(ListFailure::new(bl, None, reason, 0));
+            self.add_local_list(results, order, bl);
+            return;
+        }
 
-                        // Save raw file
-                        let cat_dir = Path::new(&self.config.base_dir).join(&bl.category);
-                        let raw_path = cat_dir.join(format!("{}.txt.raw", bl.name));
-                        if let Err(e) = std::fs::write(&raw_path, &content) {
-                            warn!("Failed to write raw file for {}: {e}", bl.name);
-                        }
+        if let Some(first) = duplicate_of {
+            debug!("  {}: same content as {first}, reusing its parse", bl.name);
+            results.content_duplicates.push((bl.name.clone(), first));
+        }
 
-                        // Save optimized file
-                        let opt_path = cat_dir.join(format!("{}.txt", bl.name));
-                        if let Err(e) = write_blocklist_file(&opt_path, &domains, None, false) {
-                            warn!("Failed to write optimized file for {}: {e}", bl.name);
-                        }
+        if count == 0 && regex_count == 0 {
+            warn!("  {}: No valid domains extracted", bl.name);
+        }
 
-                        // Update progress tracker
-                        self.progress.update(
-                            &bl.name,
-                            dl.etag.as_deref(),
-                            dl.last_modified.as_deref(),
-                            count,
-                        );
+        if self.config.count_diff {
+            results.count_changes.push(CountChange {
+                name: bl.name.clone(),
+                previous: self.progress.get(&bl.name).map(|p| p.domain_count),
+                current: count,
+            });
+        }
 
-                        category_domains
-                            .entry(bl.category.clone())
-                            .or_default()
-                            .extend(domains);
-                        successful += 1;
+        if let Some((mean, std_dev)) = self
+            .progress
+            .get(&bl.name)
+            .and_then(|p| p.count_anomaly(count))
+        {
+            warn!(
+                "  {}: {} domains is outside the recent range ({:.0} ± {:.0})",
+                bl.name,
+                format_num(count),
+                mean,
+                std_dev
+            );
+        }
 
-                        debug!("  {}: {count} domains", bl.name);
-                    }
+        // --count-only leaves local files and the progress tracker untouched
+        if !self.config.count_only && !self.config.no_individual_files {
+            // Save raw file
+            let cat_dir = self.config.list_dir(&bl.category);
+            let raw_path = cat_dir.join(format!("{}.txt.raw", bl.name));
+            let rejected_lines = match &spooled {
+                Some(spooled) if self.config.per_list_stats => {
+                    count_rejected_file_lines(spooled.path(), bl.allow_wildcards, &ctx.filter)
+                        .unwrap_or_default()
+                }
+                Some(_) => 0,
+                None if self.config.per_list_stats => {
+                    count_rejected_lines(&lines, bl.allow_wildcards, &ctx.filter)
                 }
+                None => 0,
+            };
+            let bytes = spooled
+                .as_ref()
+                .map_or(content.len(), |spooled| spooled.len() as usize);
+            let saved = match spooled {
+                Some(spooled) => spooled.persist(&raw_path),
+                None => std::fs::write(&raw_path, &content),
+            };
+            if let Err(e) = saved {
+                warn!("Failed to write raw file for {}: {e}", bl.name);
             }
 
-            pb.finish_and_clear();
-        }
+            // Save optimized file
+            let opt_path = cat_dir.join(format!("{}.txt", bl.name));
+            if let Err(e) = write_blocklist_file(
+                &opt_path,
+                &domains,
+                None,
+                OutputFormat::Hosts,
+                &self.config.hosts_ips,
+                self.config.line_ending,
+            ) {
+                warn!("Failed to write optimized file for {}: {e}", bl.name);
+            } else {
+                ctx.cache.record(content_key, &bl.name);
+            }
 
-        // Compute unique domain count (excluding NSFW)
-        let unique_domains = {
-            let mut all: HashSet<&String> = HashSet::new();
-            for (cat, domains) in &category_domains {
-                if cat != "nsfw" {
-                    all.extend(domains);
+            if self.config.per_list_stats {
+                let stats = ListStats {
+                    name: &bl.name,
+                    category: &bl.category,
+                    url: &bl.display_url,
+                    downloaded_at: chrono::Local::now().to_rfc3339(),
+                    etag: dl.etag.as_deref(),
+                    last_modified: dl.last_modified.as_deref(),
+                    bytes,
+                    domains: count,
+                    regexes: regex_count,
+                    rejected_lines,
+                };
+                let stats_path = cat_dir.join(format!("{}.stats.json", bl.name));
+                if let Err(e) = write_list_stats(&stats_path, &stats) {
+                    warn!("Failed to write stats file for {}: {e:#}", bl.name);
                 }
             }
-            all.len()
+        }
+
+        if !self.config.count_only {
+            // Update progress tracker
+            self.progress.update(
+                &bl.name,
+                dl.etag.as_deref(),
+                dl.last_modified.as_deref(),
+                Some(content_hash.as_str()).filter(|hash| !hash.is_empty()),
+                count,
+            );
+        }
+
+        results.add_list(order, bl, domains, list_regexes);
+        results.successful += 1;
+
+        if regex_count > 0 {
+            debug!("  {}: {count} domains, {regex_count} regexes", bl.name);
+        } else {
+            debug!("  {}: {count} domains", bl.name);
+        }
+    }
+
+    /// Add `bl`'s optimized file from the last run to `results`. Returns
+    /// whether there was one to load.
+    fn add_local_list(&self, results: &mut ListResults, order: usize, bl: &Blocklist) -> bool {
+        let Some(domains) = self.load_local_list(bl) else {
+            return false;
         };
+        results.add_list(order, bl, domains, self.load_local_regexes(bl));
+        true
+    }
+
+    /// Everything after the lists are collected: the blacklist, production
+    /// lists, failure and stats reports, the summary and the webhook.
+    async fn finish(
+        &self,
+        blocklists: &[Blocklist],
+        categories: &[String],
+        mut results: ListResults,
+        disabled: usize,
+        start: Instant,
+    ) -> Result<()> {
+        if !results.content_duplicates.is_empty() {
+            results.content_duplicates.sort();
+            warn!(
+                "{} lists served content identical to another list (consider removing them from the config):",
+                results.content_duplicates.len()
+            );
+            for (duplicate, first) in &results.content_duplicates {
+                warn!("  {duplicate}: same as {first}");
+            }
+        }
+
+        if let Some(counts) = &results.auto_counts {
+            print_auto_counts(counts);
+        }
+
+        let category_domains = &mut results.category_domains;
+        // Make sure every configured category gets a production file, even if empty
+        if self.config.write_empty_lists {
+            for cat in categories {
+                category_domains.entry(cat.clone()).or_default();
+            }
+        }
+
+        // Force-include blacklist domains; the whitelist runs later, so it still wins
+        if let Some(blacklist) = &self.blacklist {
+            let targets = match &self.config.blacklist_category {
+                Some(cat) => std::slice::from_ref(cat),
+                None => categories,
+            };
+            let added = blacklist.apply(category_domains, targets);
+            info!(
+                "Blacklist added {} domains missing from the source lists",
+                format_num(added)
+            );
+        }
+
+        // Compute unique domain count (excluding NSFW)
+        let unique_domains = category_domains
+            .par_iter()
+            .filter(|(cat, _)| cat.as_str() != "nsfw")
+            .flat_map_iter(|(_, domains)| domains)
+            .collect::<HashSet<&String>>()
+            .len();
 
-        let mut whitelisted = 0usize;
+        if let (Some(path), false) = (&self.config.json_errors, self.config.count_only) {
+            let json = serde_json::to_string_pretty(&results.failures)?;
+            std::fs::write(path, json)
+                .with_context(|| format!("Failed to write failure report {path}"))?;
+            debug!("Wrote {} list failures to {path}", results.failures.len());
+        }
+
+        let mut whitelisted = WhitelistMatches::default();
         let mut final_domains = unique_domains;
+        let mut master_change = None;
+        let mut collapsed_subdomains = 0usize;
 
         // Create production lists
-        if !self.config.skip_optimize {
-            let (w, f) = self.create_production_lists(&category_domains)?;
+        if self.config.refresh_one.is_some() {
+            info!("Skipping production lists after a single-list refresh (run with --skip-download to rebuild)");
+        } else if self.config.count_only {
+            let (w, f) = self.print_counts(categories, &results.category_domains)?;
+            whitelisted = w;
+            final_domains = f;
+        } else if !self.config.skip_optimize {
+            let (w, f, collapsed, change) = self.create_production_lists(
+                categories,
+                &results.category_domains,
+                results.domain_sources.as_ref(),
+                &results.regexes,
+            )?;
             whitelisted = w;
             final_domains = f;
+            collapsed_subdomains = collapsed;
+            master_change = change;
         }
 
-        let elapsed = start.elapsed();
+        let summary = RunSummary {
+            total_lists: blocklists.len(),
+            successful: results.successful,
+            skipped: results.skipped_names.len(),
+            failed: results.failures.len(),
+            disabled,
+            listed_domains: results.listed_domains,
+            unique_domains,
+            whitelisted: whitelisted.total(),
+            whitelist_matches: whitelisted,
+            final_domains,
+            collapsed_subdomains,
+            regex_entries: results.regexes.len(),
+            runtime_seconds: start.elapsed().as_secs_f64(),
+        };
+
+        if let (Some(path), false) = (&self.config.stats_json, self.config.count_only) {
+            let json = serde_json::to_string_pretty(&summary)?;
+            std::fs::write(path, json)
+                .with_context(|| format!("Failed to write run stats {path}"))?;
+            debug!("Wrote run stats to {path}");
+        }
 
         // Print summary
         if !self.config.quiet {
-            println!();
-            println!("{}", "=".repeat(60));
-            println!("{:>35}", "SUMMARY");
-            println!("{}", "=".repeat(60));
-            println!("Total lists:        {total_lists}");
-            println!("Successful:         {successful}");
-            println!("Skipped:            {skipped}");
-            println!("Failed:             {failed}");
-            println!("Unique domains:     {}", format_num(unique_domains));
-            if whitelisted > 0 {
-                println!("Whitelisted:        {}", format_num(whitelisted));
-                println!("Final count:        {}", format_num(final_domains));
-            }
-            println!("Runtime:            {:.2} seconds", elapsed.as_secs_f64());
-            println!("{}", "=".repeat(60));
-            println!();
+            summary.print();
+            if self.config.summary_lists {
+                let failed_names: Vec<String> =
+                    results.failures.iter().map(|f| f.name.clone()).collect();
+                print_name_group("Failed lists", failed_names);
+                print_name_group("Skipped lists (not modified)", results.skipped_names);
+            }
+            if self.config.top_lists > 0 {
+                self.print_top_lists(blocklists, self.config.top_lists);
+            }
+            if self.config.count_diff {
+                print_count_diff(&mut results.count_changes);
+            }
+        }
+
+        if let (Some(url), Some(change)) = (&self.config.webhook_url, master_change) {
+            self.notify_webhook(url, &summary, change).await;
         }
 
         Ok(())
     }
 
-    fn create_directories(&self, categories: &HashSet<String>) -> Result<()> {
-        std::fs::create_dir_all(&self.config.base_dir)?;
+    /// Path of the list's optimized file.
+    fn local_list_path(&self, bl: &Blocklist) -> PathBuf {
+        self.config
+            .list_dir(&bl.category)
+            .join(format!("{}.txt", bl.name))
+    }
+
+    /// Domains from the list's existing optimized file, if there is one.
+    fn load_local_list(&self, bl: &Blocklist) -> Option<HashSet<String>> {
+        let path = self.local_list_path(bl);
+        if !path.exists() {
+            return None;
+        }
+        load_domains_from_file(&path, bl.allow_wildcards).ok()
+    }
+
+    /// Pi-hole regex entries from the list's last raw download, if there is one.
+    fn load_local_regexes(&self, bl: &Blocklist) -> HashSet<String> {
+        let path = self
+            .config
+            .list_dir(&bl.category)
+            .join(format!("{}.txt.raw", bl.name));
+        std::fs::read(path)
+            .map(|content| extract_regexes(&content))
+            .unwrap_or_default()
+    }
+
+    /// Print the `n` configured lists with the most domains, using the
+    /// per-list counts recorded in the progress file.
+    fn print_top_lists(&self, blocklists: &[Blocklist], n: usize) {
+        let mut counts: Vec<(&str, &str, usize)> = blocklists
+            .iter()
+            .filter_map(|bl| {
+                self.progress
+                    .get(&bl.name)
+                    .map(|p| (bl.name.as_str(), bl.category.as_str(), p.domain_count))
+            })
+            .collect();
+        if counts.is_empty() {
+            return;
+        }
+        counts.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(b.0)));
+
+        println!("{}", "=".repeat(60));
+        println!("{:>35}", "TOP LISTS");
+        println!("{}", "=".repeat(60));
+        for (rank, (name, category, count)) in counts.iter().take(n).enumerate() {
+            println!(
+                "{:>3}. {:<30} {:<14} {:>9}",
+                rank + 1,
+                name,
+                category,
+                format_num(*count)
+            );
+        }
+        println!("{}", "=".repeat(60));
+        println!();
+    }
+
+    async fn notify_webhook(&self, url: &str, summary: &RunSummary, change: MasterChange) {
+        let delta = change.added + change.removed;
+        if delta < self.config.webhook_threshold {
+            debug!(
+                "Master changed by {delta} domains (threshold {}), skipping webhook",
+                self.config.webhook_threshold
+            );
+            return;
+        }
+
+        let payload = WebhookPayload {
+            summary,
+            added: change.added,
+            removed: change.removed,
+        };
+        match self.http_client.post_json(url, &payload).await {
+            Ok(()) => info!("Sent webhook notification ({delta} domains changed)"),
+            Err(e) => warn!("Failed to send webhook notification: {e:#}"),
+        }
+    }
+
+    /// Create every output directory and check each one is writable, so a
+    /// misconfigured destination fails before anything is downloaded.
+    fn create_directories(&self, categories: &[String]) -> Result<()> {
+        let mut dirs = vec![PathBuf::from(&self.config.base_dir)];
         for cat in categories {
-            std::fs::create_dir_all(Path::new(&self.config.base_dir).join(cat))?;
+            dirs.push(self.config.list_dir(cat));
+            dirs.push(self.config.category_prod_dir(cat).to_path_buf());
+        }
+        dirs.push(PathBuf::from(&self.config.prod_dir));
+        dirs.sort();
+        dirs.dedup();
+
+        for dir in &dirs {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+            ensure_writable(dir)?;
         }
-        std::fs::create_dir_all(&self.config.prod_dir)?;
         Ok(())
     }
 
-    fn create_production_lists(
+    /// Combine the non-NSFW categories into the master, drop `--subtract`
+    /// reference domains and apply the whitelist.
+    fn build_master(
         &self,
         category_domains: &HashMap<String, HashSet<String>>,
-    ) -> Result<(usize, usize)> {
-        info!("Creating production blocklists...");
+    ) -> Result<MasterBuild> {
+        let mut all_domains = master_union(category_domains);
 
-        // Combine all non-NSFW domains
-        let mut all_domains: HashSet<String> = HashSet::new();
-        for (cat, domains) in category_domains {
-            if cat != "nsfw" {
-                all_domains.extend(domains.iter().cloned());
+        // Drop domains already covered by the reference list
+        let reference = match &self.config.subtract {
+            Some(path) => {
+                let reference = load_domains_from_file(Path::new(path), true)?;
+                let before = all_domains.len();
+                all_domains.retain(|d| !reference.contains(d));
+                info!(
+                    "Subtracted {} domains present in {path}",
+                    format_num(before - all_domains.len())
+                );
+                Some(reference)
             }
-        }
+            None => None,
+        };
 
         // Apply whitelist filtering
         info!("Applying whitelist filtering...");
         let (filtered, removed) = self.whitelist.filter_domains(&all_domains);
 
-        // Write master file
-        let master_path = Path::new(&self.config.prod_dir).join("all_domains.txt");
-        write_blocklist_file(&master_path, &filtered, Some("Master"), false)?;
-        info!(
-            "Created Master blocklist: {} domains",
-            format_num(filtered.len())
-        );
+        Ok(MasterBuild {
+            all_domains,
+            filtered,
+            removed,
+            reference,
+        })
+    }
 
-        // Write per-category files
-        for (cat, domains) in category_domains {
-            if !domains.is_empty() {
-                let (cat_filtered, _) = self.whitelist.filter_domains(domains);
-                let cat_path = Path::new(&self.config.prod_dir).join(format!("{cat}.txt"));
-                let label = capitalize(cat);
-                write_blocklist_file(&cat_path, &cat_filtered, Some(&label), false)?;
-                info!(
-                    "Created {label} blocklist: {} domains",
+    /// A category's domains after the whitelist and `--subtract`. Non-NSFW
+    /// categories are subsets of the master, so they reuse its filtered result
+    /// instead of re-running the whitelist.
+    fn filter_category(
+        &self,
+        cat: &str,
+        domains: &HashSet<String>,
+        filtered_master: &HashSet<String>,
+        reference: Option<&HashSet<String>>,
+    ) -> HashSet<String> {
+        if cat != "nsfw" {
+            return domains
+                .iter()
+                .filter(|d| filtered_master.contains(*d))
+                .cloned()
+                .collect();
+        }
+        let (mut cat_filtered, _) = self.whitelist.filter_domains(domains);
+        if let Some(reference) = reference {
+            cat_filtered.retain(|d| !reference.contains(d));
+        }
+        cat_filtered
+    }
+
+    /// `--count-only`: print the master and per-category sizes the production
+    /// lists would have, without writing them.
+    fn print_counts(
+        &self,
+        categories: &[String],
+        category_domains: &HashMap<String, HashSet<String>>,
+    ) -> Result<(WhitelistMatches, usize)> {
+        let master = self.build_master(category_domains)?;
+
+        if !self.config.quiet {
+            println!("{}", "=".repeat(60));
+            println!("{:>35}", "COUNTS");
+            println!("{}", "=".repeat(60));
+            println!(
+                "{:<30} {:>12}",
+                "all_domains.txt",
+                format_num(master.filtered.len())
+            );
+            for cat in categories {
+                let Some(domains) = category_domains.get(cat) else {
+                    continue;
+                };
+                let cat_filtered =
+                    self.filter_category(cat, domains, &master.filtered, master.reference.as_ref());
+                println!(
+                    "{:<30} {:>12}",
+                    format!("{cat}.txt"),
                     format_num(cat_filtered.len())
                 );
-
-                if self
-                    .config
-                    .abp_lists
-                    .iter()
-                    .any(|c| c.eq_ignore_ascii_case(cat))
-                {
-                    let abp_path = Path::new(&self.config.prod_dir).join(format!("{cat}_abp.txt"));
-                    let abp_label = format!("{label} (ABP)");
-                    write_blocklist_file(&abp_path, &cat_filtered, Some(&abp_label), true)?;
-                    info!(
-                        "Created {abp_label} blocklist: {} entries",
-                        format_num(cat_filtered.len())
-                    );
-                }
             }
+            println!("{}", "=".repeat(60));
         }
 
-        // Whitelist report
-        if self.config.whitelist_report && removed > 0 {
-            let removed_set: HashSet<String> = all_domains.difference(&filtered).cloned().collect();
-            let report_path = Path::new(&self.config.prod_dir).join("whitelist_report.txt");
-            self.whitelist.generate_report(
-                report_path
-                    .to_str()
-                    .expect("report path must be valid UTF-8"),
-                &removed_set,
-            )?;
-        }
-
-        Ok((removed, filtered.len()))
+        Ok((master.removed, master.filtered.len()))
     }
-}
 
-fn process_content(content: &[u8], allow_wildcards: bool) -> HashSet<String> {
-    let text = String::from_utf8_lossy(content);
+    /// The domains to write, with subdomains of other entries dropped under
+    /// `--collapse-subdomains`.
+    fn collapse<'a>(&self, domains: &'a HashSet<String>) -> Cow<'a, HashSet<String>> {
+        if self.config.collapse_subdomains {
+            Cow::Owned(collapse_subdomains(domains))
+        } else {
+            Cow::Borrowed(domains)
+        }
+    }
+
+    fn create_production_lists(
+        &self,
+        categories: &[String],
+        category_domains: &HashMap<String, HashSet<String>>,
+        domain_sources: Option<&SourceMap>,
+        regexes: &HashSet<String>,
+    ) -> Result<(WhitelistMatches, usize, usize, Option<MasterChange>)> {
+        info!("Creating production blocklists...");
+
+        let MasterBuild {
+            all_domains,
+            filtered,
+            removed,
+            reference,
+        } = self.build_master(category_domains)?;
+
+        // Only the written files are collapsed; categories still filter
+        // against the full master.
+        let master = self.collapse(&filtered);
+        let collapsed = filtered.len() - master.len();
+        if collapsed > 0 {
+            info!(
+                "Collapsed {} subdomains already covered by a parent",
+                format_num(collapsed)
+            );
+        }
+
+        // Write master file, diffing against the previous one if a webhook needs it
+        let master_path = Path::new(&self.config.prod_dir).join("all_domains.txt");
+        let master_change = if self.config.webhook_url.is_some() {
+            let format = self.config.output_format;
+            let previous = if master_path.exists() {
+                load_written_names(&master_path, format)?
+            } else {
+                HashSet::new()
+            };
+            // Formats other than hosts don't keep wildcard keys apart, so compare names
+            let current: Cow<HashSet<String>> =
+                if matches!(format, OutputFormat::Hosts | OutputFormat::Abp) {
+                    Cow::Borrowed(master.as_ref())
+                } else {
+                    Cow::Owned(
+                        covering_names(master.iter(), format == OutputFormat::Dnsmasq)
+                            .into_iter()
+                            .map(str::to_string)
+                            .collect(),
+                    )
+                };
+            Some(MasterChange {
+                added: current.difference(&previous).count(),
+                removed: previous.difference(&current).count(),
+            })
+        } else {
+            None
+        };
+        if self.config.backup_master && master_path.exists() {
+            let backup_path = master_path.with_extension("txt.bak");
+            std::fs::rename(&master_path, &backup_path).with_context(|| {
+                format!("Failed to back up master list to {}", backup_path.display())
+            })?;
+            debug!(
+                "Backed up previous master list to {}",
+                backup_path.display()
+            );
+        }
+        write_blocklist_file(
+            &master_path,
+            &master,
+            Some("Master"),
+            self.config.output_format,
+            &self.config.hosts_ips,
+            self.config.line_ending,
+        )?;
+        info!(
+            "Created Master blocklist: {} domains",
+            format_num(master.len())
+        );
+
+        if self.config.report_homographs {
+            let homographs: HashSet<String> = filtered
+                .iter()
+                .filter(|key| is_mixed_script(key.trim_start_matches("||").trim_end_matches('^')))
+                .cloned()
+                .collect();
+            if !homographs.is_empty() {
+                warn!(
+                    "Found {} mixed-script (possible homograph) domains",
+                    format_num(homographs.len())
+                );
+            }
+            let homograph_path = Path::new(&self.config.prod_dir).join("homographs.txt");
+            write_blocklist_file(
+                &homograph_path,
+                &homographs,
+                Some("Homograph"),
+                self.config.output_format,
+                &self.config.hosts_ips,
+                self.config.line_ending,
+            )?;
+            info!(
+                "Created Homograph blocklist: {} domains",
+                format_num(homographs.len())
+            );
+        }
+
+        if let Some(bloom_path) = &self.config.export_bloom {
+            let bloom = bloom_filter(&filtered, self.config.bloom_fp_rate);
+            bloom.write(Path::new(bloom_path))?;
+            info!(
+                "Exported Bloom filter of {} domains to {bloom_path}",
+                format_num(filtered.len())
+            );
+        }
+
+        if let Some(sources) = domain_sources {
+            let sources_path = Path::new(&self.config.prod_dir).join("sources.txt");
+            write_sources_file(&sources_path, &filtered, sources, self.config.line_ending)?;
+            info!("Created source attribution for Master blocklist");
+        }
+
+        // Domains the nsfw whitelist pass removed, for the audit; the master
+        // difference below covers every other category.
+        let mut nsfw_removed: HashSet<String> = HashSet::new();
+        // Category -> its final domains, for --combined-annotated
+        let mut annotated: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+
+        // Write per-category files
+        for cat in categories {
+            let Some(domains) = category_domains.get(cat) else {
+                continue;
+            };
+            if !domains.is_empty() || self.config.write_empty_lists {
+                let cat_filtered =
+                    self.filter_category(cat, domains, &filtered, reference.as_ref());
+                if cat == "nsfw" && self.config.whitelist_audit_json.is_some() {
+                    nsfw_removed.extend(domains.difference(&cat_filtered).cloned());
+                }
+                let cat_filtered = self.collapse(&cat_filtered);
+                if self.config.combined_annotated.is_some() {
+                    annotated.insert(cat.clone(), cat_filtered.clone().into_owned());
+                }
+                let cat_path = self
+                    .config
+                    .category_prod_dir(cat)
+                    .join(format!("{cat}.txt"));
+                let label = capitalize(cat);
+                write_blocklist_file(
+                    &cat_path,
+                    &cat_filtered,
+                    Some(&label),
+                    self.config.output_format,
+                    &self.config.hosts_ips,
+                    self.config.line_ending,
+                )?;
+                info!(
+                    "Created {label} blocklist: {} domains",
+                    format_num(cat_filtered.len())
+                );
+
+                if self
+                    .config
+                    .abp_lists
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(cat))
+                {
+                    let abp_path = self
+                        .config
+                        .category_prod_dir(cat)
+                        .join(format!("{cat}_abp.txt"));
+                    let abp_label = format!("{label} (ABP)");
+                    write_blocklist_file(
+                        &abp_path,
+                        &cat_filtered,
+                        Some(&abp_label),
+                        OutputFormat::Abp,
+                        &self.config.hosts_ips,
+                        self.config.line_ending,
+                    )?;
+                    info!(
+                        "Created {abp_label} blocklist: {} entries",
+                        format_num(cat_filtered.len())
+                    );
+                }
+            }
+        }
+
+        if !regexes.is_empty() {
+            let regex_path = Path::new(&self.config.prod_dir).join("regex.list");
+            write_regex_list(&regex_path, regexes, self.config.line_ending)?;
+            info!(
+                "Created Regex blocklist: {} entries",
+                format_num(regexes.len())
+            );
+        }
+
+        if let Some(path) = &self.config.combined_annotated {
+            write_combined_annotated(Path::new(path), &annotated, self.config.line_ending)?;
+            info!("Created combined annotated list: {path}");
+        }
+
+        if self.config.overlap_report {
+            let (names, matrix) = overlap_matrix(category_domains);
+            if !self.config.quiet {
+                print_overlap_matrix(&names, &matrix);
+            }
+            let overlap_path = match &self.config.overlap_file {
+                Some(path) => PathBuf::from(path),
+                None => Path::new(&self.config.prod_dir).join("category_overlap.txt"),
+            };
+            let overlap = overlap_pairs(&names, &matrix);
+            write_overlap_report(&overlap_path, &overlap, self.config.line_ending)?;
+            info!(
+                "Created category overlap report: {} overlapping pairs",
+                overlap.len()
+            );
+        }
+
+        let removed_set: HashSet<String> = if self.config.export_allowlist.is_some()
+            || self.config.whitelist_report
+            || self.config.whitelist_audit_json.is_some()
+        {
+            all_domains.difference(&filtered).cloned().collect()
+        } else {
+            HashSet::new()
+        };
+
+        if let Some(path) = &self.config.whitelist_audit_json {
+            // Reference-list removals have no whitelist rule and are left out.
+            nsfw_removed.extend(removed_set.iter().cloned());
+            self.whitelist.write_audit(Path::new(path), &nsfw_removed)?;
+        }
+
+        if let Some(path) = &self.config.export_allowlist {
+            export_allowlist(Path::new(path), &removed_set, self.config.line_ending)?;
+        }
+
+        // Whitelist report
+        if self.config.whitelist_report && removed.total() > 0 {
+            let report_path = Path::new(&self.config.prod_dir).join("whitelist_report.txt");
+            self.whitelist.generate_report(
+                report_path
+                    .to_str()
+                    .expect("report path must be valid UTF-8"),
+                &removed_set,
+                self.config.line_ending,
+            )?;
+        }
+
+        Ok((removed, master.len(), collapsed, master_change))
+    }
+}
+
+/// Fraction of its previous size a list must lose for `--diff` to flag it.
+const SHARP_SHRINK_RATIO: f64 = 0.5;
+
+/// A downloaded list's domain count this run against the last recorded one.
+struct CountChange {
+    name: String,
+    previous: Option<usize>,
+    current: usize,
+}
+
+impl CountChange {
+    /// Whether the list lost at least `SHARP_SHRINK_RATIO` of its domains,
+    /// which usually means the upstream broke (an error page, a moved file).
+    fn shrank_sharply(&self) -> bool {
+        self.previous.is_some_and(|previous| {
+            previous > 0 && self.current as f64 <= previous as f64 * (1.0 - SHARP_SHRINK_RATIO)
+        })
+    }
+}
+
+/// `--diff`: each downloaded list's previous and new domain counts, by name.
+fn print_count_diff(changes: &mut [CountChange]) {
+    if changes.is_empty() {
+        return;
+    }
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+
+    println!("{}", "=".repeat(60));
+    println!("{:>35}", "LIST CHANGES");
+    println!("{}", "=".repeat(60));
+    println!(
+        "{:<26} {:>10} {:>10} {:>10}",
+        "List", "Previous", "Now", "Delta"
+    );
+    for change in changes.iter() {
+        let (previous, delta) = match change.previous {
+            Some(previous) => (
+                format_num(previous),
+                format_delta(change.current as i64 - previous as i64),
+            ),
+            None => ("new".to_string(), String::new()),
+        };
+        let flag = if change.shrank_sharply() { "  !!" } else { "" };
+        println!(
+            "{:<26} {:>10} {:>10} {:>10}{flag}",
+            change.name,
+            previous,
+            format_num(change.current),
+            delta
+        );
+    }
+    println!("{}", "=".repeat(60));
+
+    let shrunk = changes.iter().filter(|c| c.shrank_sharply()).count();
+    if shrunk > 0 {
+        println!(
+            "!! {shrunk} list(s) lost at least {:.0}% of their domains; check the source",
+            SHARP_SHRINK_RATIO * 100.0
+        );
+    }
+    println!();
+}
+
+/// `+1,234` / `-1,234` / `0`.
+fn format_delta(delta: i64) -> String {
+    let magnitude = format_num(delta.unsigned_abs() as usize);
+    match delta.signum() {
+        1 => format!("+{magnitude}"),
+        -1 => format!("-{magnitude}"),
+        _ => magnitude,
+    }
+}
+
+fn print_name_group(title: &str, mut names: Vec<String>) {
+    if names.is_empty() {
+        return;
+    }
+    names.sort();
+    println!("{title} ({}):", names.len());
+    for name in &names {
+        println!("  - {name}");
+    }
+    println!();
+}
+
+/// Fail unless `category` is one of the configured `categories`, naming them.
+fn check_category(category: &str, categories: &[String]) -> Result<()> {
+    if !categories.iter().any(|c| c == category) {
+        bail!(
+            "Unknown blacklist category '{category}'; expected one of: {}",
+            categories.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Intersection sizes between every pair of categories, with categories sorted
+/// by name. The diagonal holds each category's own size.
+fn overlap_matrix(
+    category_domains: &HashMap<String, HashSet<String>>,
+) -> (Vec<&str>, Vec<Vec<usize>>) {
+    let mut names: Vec<&str> = category_domains.keys().map(String::as_str).collect();
+    names.sort();
+
+    let mut matrix = vec![vec![0usize; names.len()]; names.len()];
+    for (i, a) in names.iter().enumerate() {
+        let set_a = &category_domains[*a];
+        matrix[i][i] = set_a.len();
+        for (j, b) in names.iter().enumerate().skip(i + 1) {
+            let set_b = &category_domains[*b];
+            let (small, large) = if set_a.len() <= set_b.len() {
+                (set_a, set_b)
+            } else {
+                (set_b, set_a)
+            };
+            let shared = small.iter().filter(|d| large.contains(*d)).count();
+            matrix[i][j] = shared;
+            matrix[j][i] = shared;
+        }
+    }
+    (names, matrix)
+}
+
+fn print_overlap_matrix(names: &[&str], matrix: &[Vec<usize>]) {
+    let width = names
+        .iter()
+        .map(|n| n.len())
+        .chain(matrix.iter().flatten().map(|n| format_num(*n).len()))
+        .max()
+        .unwrap_or(0);
+
+    println!();
+    println!("Category overlap (shared domains, before whitelisting):");
+    print!("{:width$}", "");
+    for name in names {
+        print!("  {name:>width$}");
+    }
+    println!();
+    for (name, row) in names.iter().zip(matrix) {
+        print!("{name:width$}");
+        for count in row {
+            print!("  {:>width$}", format_num(*count));
+        }
+        println!();
+    }
+    println!();
+}
+
+/// Domain -> (config position, name) of the list it is attributed to.
+type SourceMap = HashMap<String, (usize, Arc<str>)>;
+
+/// Remember which list first contributed each master (non-NSFW) domain, where
+/// "first" is the earliest in the config (`order`) rather than the first to
+/// finish downloading, so attribution does not depend on thread timing. Only
+/// one source is kept per domain rather than every list it appears in.
+fn record_sources(
+    sources: &mut Option<SourceMap>,
+    order: usize,
+    bl: &Blocklist,
+    domains: &HashSet<String>,
+) {
+    let Some(sources) = sources else {
+        return;
+    };
+    if bl.category == "nsfw" {
+        return;
+    }
+    let name: Arc<str> = Arc::from(bl.name.as_str());
+    for domain in domains {
+        match sources.get_mut(domain) {
+            Some(existing) if existing.0 > order => *existing = (order, Arc::clone(&name)),
+            Some(_) => {}
+            None => {
+                sources.insert(domain.clone(), (order, Arc::clone(&name)));
+            }
+        }
+    }
+}
+
+/// Add a list's domains to its category, counting them towards the pre-dedup
+/// total unless the list is NSFW (which the master, and so the unique count,
+/// leaves out).
+///
+/// With `--auto-categorize` (`auto_counts` is `Some`), domains from `auto`
+/// lists go to the category their name suggests instead, and the tallies are
+/// counted per assigned category (`unclassified` stays in the list's own).
+fn add_list_domains(
+    category_domains: &mut HashMap<String, HashSet<String>>,
+    listed_domains: &mut usize,
+    auto_counts: &mut Option<HashMap<&'static str, usize>>,
+    bl: &Blocklist,
+    domains: HashSet<String>,
+) {
+    if bl.category != "nsfw" {
+        *listed_domains += domains.len();
+    }
+    match auto_counts {
+        // NSFW lists are never spread into categories that feed the master
+        Some(counts) if bl.auto_categorize && bl.category != "nsfw" => {
+            for domain in domains {
+                let assigned = classify(&domain);
+                *counts
+                    .entry(assigned.unwrap_or("unclassified"))
+                    .or_default() += 1;
+                let category = assigned.unwrap_or(&bl.category);
+                category_domains
+                    .entry(category.to_string())
+                    .or_default()
+                    .insert(domain);
+            }
+        }
+        _ => {
+            category_domains
+                .entry(bl.category.clone())
+                .or_default()
+                .extend(domains);
+        }
+    }
+}
+
+fn print_auto_counts(counts: &HashMap<&'static str, usize>) {
+    let total: usize = counts.values().sum();
+    if total == 0 {
+        return;
+    }
+    let breakdown: Vec<String> = CATEGORIES
+        .iter()
+        .chain(&["unclassified"])
+        .map(|cat| {
+            format!(
+                "{cat} {}",
+                format_num(counts.get(cat).copied().unwrap_or(0))
+            )
+        })
+        .collect();
+    info!(
+        "Auto-categorized {} domains: {}",
+        format_num(total),
+        breakdown.join(", ")
+    );
+}
+
+/// Add a list's regex entries to the run-wide set, leaving out NSFW lists as
+/// the Master list does.
+fn record_regexes(regexes: &mut HashSet<String>, bl: &Blocklist, found: HashSet<String>) {
+    if bl.category != "nsfw" {
+        regexes.extend(found);
+    }
+}
+
+fn write_regex_list(path: &Path, regexes: &HashSet<String>, line_ending: LineEnding) -> Result<()> {
+    let mut sorted: Vec<&String> = regexes.iter().collect();
+    sorted.sort();
+
+    let mut w = create_output(path, line_ending)?;
+
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    writeln!(w, "# Pi-hole Regex Blocklist")?;
+    writeln!(w, "# Last updated: {now}")?;
+    writeln!(w, "# Total entries: {}", sorted.len())?;
+    writeln!(w)?;
+
+    for regex in sorted {
+        writeln!(w, "{regex}")?;
+    }
+
+    Ok(())
+}
+
+fn write_sources_file(
+    path: &Path,
+    domains: &HashSet<String>,
+    sources: &SourceMap,
+    line_ending: LineEnding,
+) -> Result<()> {
+    let mut sorted: Vec<&String> = domains.iter().collect();
+    sorted.sort();
+
+    let mut w = create_output(path, line_ending)?;
+
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    writeln!(
+        w,
+        "# Pi-hole Master Blocklist sources (first list to contribute each domain)"
+    )?;
+    writeln!(w, "# Last updated: {now}")?;
+    writeln!(w)?;
+
+    for domain in sorted {
+        let source = sources.get(domain).map_or("unknown", |(_, name)| name);
+        writeln!(w, "{domain} {source}")?;
+    }
+
+    Ok(())
+}
+
+/// Whether the RFC 3339 `last_download` time is less than `interval` before
+/// `now`. Unparseable or future times count as stale, so the list is fetched.
+fn downloaded_within(
+    last_download: &str,
+    interval: Duration,
+    now: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    let Ok(last) = chrono::DateTime::parse_from_rfc3339(last_download) else {
+        return false;
+    };
+    let age = now.signed_duration_since(last.with_timezone(&chrono::Utc));
+    age >= chrono::Duration::zero()
+        && chrono::Duration::from_std(interval).map_or(true, |interval| age < interval)
+}
+
+/// Most category pairs written by `--overlap-report`; the rest are only counted.
+const MAX_OVERLAP_PAIRS: usize = 200;
+
+/// One pair of categories that share domains.
+#[derive(Debug, PartialEq)]
+struct CategoryOverlap<'a> {
+    first: &'a str,
+    second: &'a str,
+    shared: usize,
+    /// `shared` as a percentage of the smaller category.
+    percent: f64,
+}
+
+/// Every pair of categories in an [`overlap_matrix`] that shares domains, most
+/// shared first.
+fn overlap_pairs<'a>(names: &[&'a str], matrix: &[Vec<usize>]) -> Vec<CategoryOverlap<'a>> {
+    let mut overlap = Vec::new();
+    for (i, first) in names.iter().enumerate() {
+        for (j, second) in names.iter().enumerate().skip(i + 1) {
+            let shared = matrix[i][j];
+            if shared > 0 {
+                overlap.push(CategoryOverlap {
+                    first,
+                    second,
+                    shared,
+                    percent: shared as f64 * 100.0 / matrix[i][i].min(matrix[j][j]) as f64,
+                });
+            }
+        }
+    }
+    overlap.sort_by(|x, y| {
+        y.shared
+            .cmp(&x.shared)
+            .then_with(|| (x.first, x.second).cmp(&(y.first, y.second)))
+    });
+    overlap
+}
+
+fn write_overlap_report(
+    path: &Path,
+    overlap: &[CategoryOverlap],
+    line_ending: LineEnding,
+) -> Result<()> {
+    let mut w = create_output(path, line_ending)?;
+
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    writeln!(
+        w,
+        "# Pi-hole category overlap: category other-category shared percent-of-smaller"
+    )?;
+    writeln!(w, "# Last updated: {now}")?;
+    writeln!(w)?;
+
+    for pair in overlap.iter().take(MAX_OVERLAP_PAIRS) {
+        writeln!(
+            w,
+            "{} {} {} {:.1}%",
+            pair.first, pair.second, pair.shared, pair.percent
+        )?;
+    }
+    if overlap.len() > MAX_OVERLAP_PAIRS {
+        writeln!(
+            w,
+            "# {} more overlapping pairs not shown",
+            overlap.len() - MAX_OVERLAP_PAIRS
+        )?;
+    }
+
+    Ok(())
+}
+
+/// `--combined-annotated`: every category's domains in one file, sorted by
+/// category then domain, each tagged `# [category]`. A domain in several
+/// categories appears once per category.
+fn write_combined_annotated(
+    path: &Path,
+    categories: &BTreeMap<String, HashSet<String>>,
+    line_ending: LineEnding,
+) -> Result<()> {
+    let mut w = create_output(path, line_ending)?;
+
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    writeln!(w, "# Pi-hole combined blocklist, annotated by category")?;
+    writeln!(w, "# Last updated: {now}")?;
+    writeln!(w)?;
+
+    for (category, domains) in categories {
+        let mut sorted: Vec<&String> = domains.iter().collect();
+        sorted.sort();
+        for domain in sorted {
+            writeln!(w, "{domain} # [{category}]")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Clean a single local hosts/AdBlock/plain file into the normalized, sorted,
+/// deduplicated output format. ABP wildcard entries are kept as `||domain^`.
+pub fn normalize_file(input: &str, output: &str, line_ending: LineEnding) -> Result<usize> {
+    let domains = load_domains_from_file(Path::new(input), true)?;
+    write_blocklist_file(
+        Path::new(output),
+        &domains,
+        Some("Normalized"),
+        OutputFormat::Hosts,
+        &[],
+        line_ending,
+    )?;
+    Ok(domains.len())
+}
+
+/// Largest body a gzip-compressed download may expand to, so a corrupt or
+/// hostile file can't exhaust memory.
+const MAX_GUNZIPPED_BYTES: u64 = 1 << 30;
+
+/// Decompress `content` if it is gzip data (by its magic bytes); anything else
+/// is returned unchanged.
+fn gunzip_if_compressed(content: Vec<u8>) -> Result<Vec<u8>> {
+    if !content.starts_with(&[0x1f, 0x8b]) {
+        return Ok(content);
+    }
+    let mut decoded = Vec::new();
+    MultiGzDecoder::new(content.as_slice())
+        .take(MAX_GUNZIPPED_BYTES + 1)
+        .read_to_end(&mut decoded)
+        .context("Failed to decompress gzip download")?;
+    if decoded.len() as u64 > MAX_GUNZIPPED_BYTES {
+        bail!("Gzip download expands past {MAX_GUNZIPPED_BYTES} bytes");
+    }
+    Ok(decoded)
+}
+
+/// Bytes of a spooled download read up front to sniff its format.
+const SNIFF_BYTES: usize = 1024;
+
+/// Split a download into its in-memory bytes, or, for a spooled plain-text
+/// list, its first `SNIFF_BYTES` plus the spool file to read lines from.
+/// Spooled gzip and JSON bodies need the whole body, so they are read back.
+fn open_body(bl: &Blocklist, body: Body) -> std::io::Result<(Vec<u8>, Option<SpooledBody>)> {
+    let spooled = match body {
+        Body::Memory(content) => return Ok((content, None)),
+        Body::Spooled(spooled) => spooled,
+    };
+    let mut head = Vec::with_capacity(SNIFF_BYTES);
+    std::fs::File::open(spooled.path())?
+        .take(SNIFF_BYTES as u64)
+        .read_to_end(&mut head)?;
+    let gzip = head.starts_with(&[0x1f, 0x8b]);
+    let json = bl.json_path.is_some()
+        || head
+            .iter()
+            .find(|b| !b.is_ascii_whitespace())
+            .is_some_and(|b| matches!(b, b'{' | b'['));
+    if gzip || json {
+        return Ok((Body::Spooled(spooled).into_bytes()?, None));
+    }
+    Ok((head, Some(spooled)))
+}
+
+/// Call `f` with each raw line of the file at `path`, terminator included,
+/// without loading the whole file.
+fn for_each_file_line(path: &Path, mut f: impl FnMut(&[u8])) -> std::io::Result<()> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        f(&line);
+        line.clear();
+    }
+    Ok(())
+}
+
+/// `line` decoded like `String::from_utf8_lossy(..).lines()` would yield it.
+fn decode_line(line: &[u8]) -> Cow<'_, str> {
+    let line = match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => line,
+    };
+    String::from_utf8_lossy(line)
+}
+
+/// SHA-256 and invalid UTF-8 count of a spooled download, as `sha256_hex`
+/// and `count_invalid_utf8` would give for it in memory.
+fn scan_spooled(path: &Path) -> std::io::Result<(String, usize)> {
+    let mut hasher = Sha256::new();
+    let mut invalid = 0;
+    for_each_file_line(path, |line| {
+        hasher.update(line);
+        invalid += count_invalid_utf8(line);
+    })?;
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok((hash, invalid))
+}
+
+/// `process_content` and `extract_regexes` over a spooled download, reading it
+/// line by line.
+fn process_spooled(
+    path: &Path,
+    allow_wildcards: bool,
+    filter: &ContentFilter,
+) -> std::io::Result<(HashSet<String>, HashSet<String>)> {
+    let mut domains = HashSet::new();
+    let mut regexes = HashSet::new();
+    for_each_file_line(path, |line| {
+        let line = decode_line(line);
+        add_line_keys(&mut domains, &line, allow_wildcards, filter);
+        if let Some(regex) = pihole_regex(&line) {
+            regexes.insert(regex.to_string());
+        }
+    })?;
+    Ok((domains, regexes))
+}
+
+/// Whether a download is an HTML page (an error or "this list has moved"
+/// page served with 200 OK) rather than a list, judging by its Content-Type
+/// or an opening `<!DOCTYPE` / `<html` tag.
+fn looks_like_html(content_type: Option<&str>, content: &[u8]) -> bool {
+    if content_type.is_some_and(|ct| {
+        ct.split(';')
+            .next()
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+    }) {
+        return true;
+    }
+    let body = content.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(content);
+    let start = body
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(body.len());
+    let head = &body[start..body.len().min(start + 9)];
+    [b"<!doctype".as_slice(), b"<html"]
+        .iter()
+        .any(|tag| head.len() >= tag.len() && head[..tag.len()].eq_ignore_ascii_case(tag))
+}
+
+/// The first list to yield each distinct download in a run, keyed by content
+/// hash, so a list mirrored under another URL is parsed only once: later
+/// copies read back the first list's optimized file. Only names are kept, not
+/// parse results. The parse flags are part of the key since they change what
+/// the same bytes yield.
+#[derive(Default)]
+struct ContentCache {
+    /// (content hash, `abp`, JSON path) -> first list
+    first: HashMap<ContentKey, String>,
+}
+
+type ContentKey = (String, bool, Option<String>);
+
+impl ContentCache {
+    fn key(content_hash: String, bl: &Blocklist) -> ContentKey {
+        (content_hash, bl.allow_wildcards, bl.json_path.clone())
+    }
+
+    /// The list whose optimized file holds the parse of `key`'s content.
+    fn first(&self, key: &ContentKey) -> Option<&str> {
+        self.first.get(key).map(String::as_str)
+    }
+
+    /// Note that `name`'s optimized file now holds `key`'s parse. Call only
+    /// once the file is written, so a rejected list is never reused.
+    fn record(&mut self, key: ContentKey, name: &str) {
+        self.first.entry(key).or_insert_with(|| name.to_string());
+    }
+}
+
+/// Source-line filters for downloaded content. The optimizer's own files are
+/// re-read with the default, which none of their lines trip.
+#[derive(Default)]
+struct ContentFilter<'a> {
+    /// Skip hosts lines pointing anywhere but these addresses (e.g. internal
+    /// DNS mappings).
+    sinkhole_ips: Option<HashSet<&'a str>>,
+    /// Drop `*.domain` lines on non-`abp` sources instead of flattening them
+    /// to the apex domain.
+    drop_star_wildcards: bool,
+    /// Keep `$dnstype=` rules as whole-domain blocks instead of skipping them.
+    keep_dnstype_rules: bool,
+    /// Recover the host from hosts lines written as URLs or `host:port`.
+    salvage_host_urls: bool,
+}
+
+impl ContentFilter<'_> {
+    /// Whether `line` is skipped before parsing.
+    fn drops(&self, line: &str, allow_wildcards: bool) -> bool {
+        if let (Some(allowed), Some(ip)) = (&self.sinkhole_ips, hosts_line_ip(line)) {
+            if !allowed.contains(ip) {
+                return true;
+            }
+        }
+        (self.drop_star_wildcards && !allow_wildcards && line.trim().starts_with("*."))
+            || (!self.keep_dnstype_rules && has_dnstype_modifier(line))
+    }
+}
+
+/// Parse one source line, falling back to URL salvage when `filter` allows it.
+fn parse_line(line: &str, allow_wildcards: bool, filter: &ContentFilter) -> Vec<Entry> {
+    let entries = extract_entries(line, allow_wildcards);
+    if entries.is_empty() && filter.salvage_host_urls {
+        return salvage_hosts_url(line).into_iter().collect();
+    }
+    entries
+}
+
+/// Content smaller than this is parsed on the calling thread; splitting it
+/// across the pool costs more than the parse itself.
+const PARALLEL_PARSE_MIN_BYTES: usize = 1 << 20;
+
+/// Union of every non-NSFW category, built on the rayon pool.
+fn master_union(category_domains: &HashMap<String, HashSet<String>>) -> HashSet<String> {
+    category_domains
+        .par_iter()
+        .filter(|(cat, _)| cat.as_str() != "nsfw")
+        .flat_map_iter(|(_, domains)| domains.iter().cloned())
+        .collect()
+}
+
+/// Whether `len` bytes of content are parsed in parallel: only large content,
+/// and only when the pool (sized by `--threads`) has more than one thread.
+fn parse_in_parallel(len: usize) -> bool {
+    len >= PARALLEL_PARSE_MIN_BYTES && rayon::current_num_threads() > 1
+}
+
+/// Parse list content into entry keys, applying `filter` to each source line.
+/// Large lists are split into line chunks parsed on the rayon pool, so
+/// `--threads 1` keeps parsing single-threaded.
+fn process_content(
+    content: &[u8],
+    allow_wildcards: bool,
+    filter: &ContentFilter,
+) -> HashSet<String> {
+    let text = String::from_utf8_lossy(content);
+    if parse_in_parallel(text.len()) {
+        return process_lines_parallel(&text, allow_wildcards, filter);
+    }
     let mut domains = HashSet::new();
     for line in text.lines() {
-        if let Some(entry) = extract_entry(line, allow_wildcards) {
-            domains.insert(entry.to_key());
+        add_line_keys(&mut domains, line, allow_wildcards, filter);
+    }
+    domains
+}
+
+/// `process_content` over chunks of `text` in parallel, each worker filling
+/// its own set; the sets are merged smaller-into-larger.
+fn process_lines_parallel(
+    text: &str,
+    allow_wildcards: bool,
+    filter: &ContentFilter,
+) -> HashSet<String> {
+    text.par_lines()
+        .fold(HashSet::new, |mut domains, line| {
+            add_line_keys(&mut domains, line, allow_wildcards, filter);
+            domains
+        })
+        .reduce(HashSet::new, |mut a, mut b| {
+            if a.len() < b.len() {
+                std::mem::swap(&mut a, &mut b);
+            }
+            a.extend(b);
+            a
+        })
+}
+
+/// Add the entry keys on one source line to `domains`, unless `filter` skips it.
+fn add_line_keys(
+    domains: &mut HashSet<String>,
+    line: &str,
+    allow_wildcards: bool,
+    filter: &ContentFilter,
+) {
+    if filter.drops(line, allow_wildcards) {
+        return;
+    }
+    domains.extend(
+        parse_line(line, allow_wildcards, filter)
+            .into_iter()
+            .map(|entry| entry.to_key()),
+    );
+}
+
+/// The line-based text to parse for `bl`. JSON feeds, flagged with
+/// `format=json` or detected by a leading `{`/`[` that parses as JSON, are
+/// flattened to one domain per line; anything else is returned as is.
+fn list_lines<'c>(bl: &Blocklist, content: &'c [u8]) -> Cow<'c, [u8]> {
+    let looks_like_json = content
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|b| matches!(b, b'{' | b'['));
+    if bl.json_path.is_none() && !looks_like_json {
+        return Cow::Borrowed(content);
+    }
+
+    let path = bl.json_path.as_deref().unwrap_or_default();
+    match json_feed_lines(content, path) {
+        Some(lines) => Cow::Owned(lines),
+        None => {
+            if bl.json_path.is_some() {
+                warn!(
+                    "  {}: No JSON domain array at '{path}', parsing as plain text",
+                    bl.name
+                );
+            }
+            Cow::Borrowed(content)
+        }
+    }
+}
+
+/// The strings in the JSON array at dotted `path` (object keys or array
+/// indexes), one per line. An empty path means the root array or a top-level
+/// `domains` key. `None` if `content` isn't JSON or has no such array.
+fn json_feed_lines(content: &[u8], path: &str) -> Option<Vec<u8>> {
+    let value: serde_json::Value = serde_json::from_slice(content).ok()?;
+    let target = if path.is_empty() {
+        if value.is_array() {
+            &value
+        } else {
+            value.get("domains")?
+        }
+    } else {
+        path.split('.')
+            .try_fold(&value, |v, key| match key.parse::<usize>() {
+                Ok(index) if v.is_array() => v.get(index),
+                _ => v.get(key),
+            })?
+    };
+
+    let mut lines = String::new();
+    for domain in target
+        .as_array()?
+        .iter()
+        .filter_map(serde_json::Value::as_str)
+    {
+        lines.push_str(domain);
+        lines.push('\n');
+    }
+    Some(lines.into_bytes())
+}
+
+/// Pi-hole regex entries in `content`, which `process_content` skips because
+/// they aren't domains.
+fn extract_regexes(content: &[u8]) -> HashSet<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter_map(pihole_regex)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Fail unless a file can be created in `dir`.
+fn ensure_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    std::fs::write(&probe, b"")
+        .with_context(|| format!("Output directory {} is not writable", dir.display()))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Randomize download order, reproducibly when `seed` is given.
+fn shuffle_queue<T>(queue: &mut [T], seed: Option<u64>) {
+    match seed {
+        Some(seed) => queue.shuffle(&mut StdRng::seed_from_u64(seed)),
+        None => queue.shuffle(&mut rand::rng()),
+    }
+}
+
+fn write_list_stats(path: &Path, stats: &ListStats) -> Result<()> {
+    let json = serde_json::to_string_pretty(stats)?;
+    std::fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Lines of `content` that are neither blank, comments, entries nor Pi-hole
+/// regexes, i.e. what parsing had to throw away.
+fn count_rejected_lines(content: &[u8], allow_wildcards: bool, filter: &ContentFilter) -> usize {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter(|line| is_rejected_line(line, allow_wildcards, filter))
+        .count()
+}
+
+/// `count_rejected_lines` for a spooled download, reading it line by line.
+fn count_rejected_file_lines(
+    path: &Path,
+    allow_wildcards: bool,
+    filter: &ContentFilter,
+) -> std::io::Result<usize> {
+    let mut rejected = 0;
+    for_each_file_line(path, |line| {
+        if is_rejected_line(&decode_line(line), allow_wildcards, filter) {
+            rejected += 1;
         }
+    })?;
+    Ok(rejected)
+}
+
+/// A non-blank, non-comment line that is dropped by `filter` or yields neither
+/// an entry nor a regex.
+fn is_rejected_line(line: &str, allow_wildcards: bool, filter: &ContentFilter) -> bool {
+    let line = line.trim();
+    !line.is_empty()
+        && !line.starts_with(['#', '!'])
+        && (filter.drops(line, allow_wildcards)
+            || (parse_line(line, allow_wildcards, filter).is_empty()
+                && pihole_regex(line).is_none()))
+}
+
+/// Clear `bl`'s conditional-request validators when its local file at `path`
+/// doesn't exist, so the server can't answer 304. Returns whether any were cleared.
+fn clear_validators_if_missing(bl: &mut Blocklist, path: &Path) -> bool {
+    if (bl.etag.is_none() && bl.last_modified.is_none()) || path.exists() {
+        return false;
     }
-    domains
+    bl.etag = None;
+    bl.last_modified = None;
+    true
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Number of invalid UTF-8 sequences in `content`, i.e. how many U+FFFD
+/// replacement characters `String::from_utf8_lossy` introduces.
+fn count_invalid_utf8(content: &[u8]) -> usize {
+    content
+        .utf8_chunks()
+        .filter(|chunk| !chunk.invalid().is_empty())
+        .count()
 }
 
 fn load_domains_from_file(path: &Path, allow_wildcards: bool) -> Result<HashSet<String>> {
     let content =
         std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
-    Ok(process_content(&content, allow_wildcards))
+    Ok(process_content(
+        &content,
+        allow_wildcards,
+        &ContentFilter::default(),
+    ))
+}
+
+/// A Bloom filter of the domains in `keys`. Wildcard keys are inserted as
+/// their bare domain so lookups by name find them; the subdomains they cover
+/// are not represented.
+fn bloom_filter(keys: &HashSet<String>, fp_rate: f64) -> BloomFilter {
+    let mut bloom = BloomFilter::with_rate(keys.len(), fp_rate);
+    for key in keys {
+        bloom.insert(Entry::from_key(key).domain());
+    }
+    bloom
 }
 
-fn write_blocklist_file(
+pub fn write_blocklist_file(
     path: &Path,
     domains: &HashSet<String>,
     label: Option<&str>,
-    force_abp: bool,
+    format: OutputFormat,
+    hosts_ips: &[IpAddr],
+    line_ending: LineEnding,
 ) -> Result<()> {
     let mut sorted: Vec<&String> = domains.iter().collect();
     sorted.sort();
 
-    let file = std::fs::File::create(path)
-        .with_context(|| format!("Failed to create {}", path.display()))?;
-    let mut w = std::io::BufWriter::new(file);
+    let mut w = create_output(path, line_ending)?;
 
-    let label = label.unwrap_or("Optimized");
-    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
-
-    writeln!(w, "# Pi-hole {label} Blocklist")?;
-    writeln!(w, "# Last updated: {now}")?;
-    writeln!(w, "# Total domains: {}", sorted.len())?;
-    writeln!(w)?;
+    // Formats whose rules cover subdomains write one bare name per domain, so
+    // the header total counts those names rather than the entries
+    let names = match format {
+        OutputFormat::Rpz | OutputFormat::Domains | OutputFormat::Adguard => {
+            Some(covering_names(sorted.iter().copied(), false))
+        }
+        OutputFormat::Dnsmasq => Some(covering_names(sorted.iter().copied(), true)),
+        OutputFormat::Hosts | OutputFormat::Abp => None,
+    };
+    let total = names.as_ref().map_or(sorted.len(), BTreeSet::len);
 
-    for domain in sorted {
-        let line = if force_abp {
-            format_abp_line(domain)
+    let label = label.unwrap_or("Optimized");
+    let now = chrono::Local::now();
+    if format == OutputFormat::Adguard {
+        // The metadata AdGuard Home shows for a subscribed filter list
+        writeln!(w, "! Title: Pi-hole {label} Blocklist")?;
+        writeln!(w, "! Version: {}", now.format("%Y%m%d%H%M%S"))?;
+        writeln!(w, "! Last modified: {}", now.format("%Y-%m-%dT%H:%M:%S%:z"))?;
+        writeln!(w, "! Total domains: {total}")?;
+        writeln!(w, "!")?;
+    } else {
+        let comment = if format == OutputFormat::Rpz {
+            ";"
         } else {
-            format_blocklist_line(domain)
+            "#"
         };
-        writeln!(w, "{line}")?;
+        writeln!(w, "{comment} Pi-hole {label} Blocklist")?;
+        writeln!(
+            w,
+            "{comment} Last updated: {}",
+            now.format("%Y-%m-%d %H:%M:%S")
+        )?;
+        writeln!(w, "{comment} Total domains: {total}")?;
+        writeln!(w)?;
+    }
+
+    // Hosts and dnsmasq lines cycle through `hosts_ips` so block-page traffic
+    // is spread across them; ABP and RPZ lines don't name an address.
+    let hosts_ips: Vec<String> = if hosts_ips.is_empty() {
+        vec![DEFAULT_SINKHOLE_IP.to_string()]
+    } else {
+        hosts_ips.iter().map(IpAddr::to_string).collect()
+    };
+    match (format, names) {
+        (OutputFormat::Rpz, Some(names)) => {
+            write_rpz_header(&mut w, now.timestamp())?;
+            for domain in names {
+                writeln!(w, "{domain} CNAME .")?;
+                writeln!(w, "*.{domain} CNAME .")?;
+            }
+        }
+        (OutputFormat::Dnsmasq, Some(names)) => {
+            for (i, domain) in names.into_iter().enumerate() {
+                writeln!(w, "address=/{domain}/{}", hosts_ips[i % hosts_ips.len()])?;
+            }
+        }
+        // `||domain^` already covers subdomains, so exact and wildcard
+        // entries for a domain are one rule
+        (OutputFormat::Adguard, Some(names)) => {
+            for domain in names {
+                writeln!(w, "||{domain}^")?;
+            }
+        }
+        (OutputFormat::Domains, Some(names)) => {
+            for domain in names {
+                writeln!(w, "{domain}")?;
+            }
+        }
+        _ => {
+            for (i, domain) in sorted.into_iter().enumerate() {
+                let line = if format == OutputFormat::Abp {
+                    format_abp_line(domain)
+                } else {
+                    format_blocklist_line(domain, &hosts_ips[i % hosts_ips.len()])
+                };
+                writeln!(w, "{line}")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Bare domain names for formats whose rules always cover subdomains (RPZ
+/// with its `*.` rows, dnsmasq `address=`, AdGuard `||domain^`, and the domain
+/// lists AdGuard Home and Blocky read), so a wildcard entry and the exact
+/// entry for a domain become one name. With `collapse`, names under another
+/// listed name are dropped as already covered.
+fn covering_names<'a>(
+    keys: impl IntoIterator<Item = &'a String>,
+    collapse: bool,
+) -> BTreeSet<&'a str> {
+    let names: BTreeSet<&str> = keys
+        .into_iter()
+        .map(|key| key.trim_start_matches("||").trim_end_matches('^'))
+        .collect();
+    if !collapse {
+        return names;
     }
+    names
+        .iter()
+        .filter(|name| !parent_domains(name).any(|parent| names.contains(parent)))
+        .copied()
+        .collect()
+}
+
+/// `--collapse-subdomains`: drop every entry with a parent domain also in the
+/// set, exact or wildcard, since a blocker that covers subdomains already
+/// blocks it through the parent.
+fn collapse_subdomains(domains: &HashSet<String>) -> HashSet<String> {
+    let names: HashSet<&str> = domains
+        .iter()
+        .map(|key| key.trim_start_matches("||").trim_end_matches('^'))
+        .collect();
+    domains
+        .iter()
+        .filter(|key| {
+            let name = key.trim_start_matches("||").trim_end_matches('^');
+            !parent_domains(name).any(|parent| names.contains(parent))
+        })
+        .cloned()
+        .collect()
+}
 
+/// `$TTL`, SOA and NS records opening an RPZ zone. The serial is the Unix time
+/// of the write, so every regenerated zone has a higher serial than the last
+/// and secondaries pick it up.
+fn write_rpz_header(w: &mut impl Write, serial: i64) -> Result<()> {
+    writeln!(w, "$TTL 2h")?;
+    writeln!(
+        w,
+        "@ IN SOA localhost. root.localhost. ({serial} 1h 15m 30d 2h)"
+    )?;
+    writeln!(w, "  IN NS localhost.")?;
+    writeln!(w)?;
     Ok(())
 }
 
-fn format_blocklist_line(key: &str) -> String {
+/// Domains named in a production list written by `write_blocklist_file` in
+/// `format`, as `covering_names` would produce them for the formats it serves.
+fn load_written_names(path: &Path, format: OutputFormat) -> Result<HashSet<String>> {
+    match format {
+        OutputFormat::Hosts | OutputFormat::Abp => return load_domains_from_file(path, true),
+        OutputFormat::Domains => return load_domains_from_file(path, false),
+        OutputFormat::Rpz | OutputFormat::Dnsmasq | OutputFormat::Adguard => {}
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| match format {
+            OutputFormat::Rpz => line
+                .strip_suffix(" CNAME .")
+                .filter(|owner| !owner.starts_with("*.")),
+            OutputFormat::Adguard => line
+                .strip_prefix("||")
+                .and_then(|rest| rest.strip_suffix('^')),
+            _ => line
+                .strip_prefix("address=/")
+                .and_then(|rest| rest.split_once('/'))
+                .map(|(domain, _)| domain),
+        })
+        .map(str::to_string)
+        .collect())
+}
+
+fn format_blocklist_line(key: &str, ip: &str) -> String {
     if key.starts_with("||") {
         key.to_string()
     } else {
-        format!("0.0.0.0 {key}")
+        format!("{ip} {key}")
     }
 }
 
@@ -404,27 +2473,866 @@ fn capitalize(s: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client::RequestSpec;
+    use crate::test_util::TempDir;
 
     #[test]
     fn process_content_emits_wildcards_when_enabled() {
-        let set = process_content(b"||foo.com^\n*.bar.com\n0.0.0.0 baz.com\n", true);
+        let set = process_content(
+            b"||foo.com^\n*.bar.com\n0.0.0.0 baz.com\n",
+            true,
+            &ContentFilter::default(),
+        );
         assert!(set.contains("||foo.com^"));
         assert!(set.contains("||bar.com^"));
         assert!(set.contains("baz.com"));
     }
 
+    #[test]
+    fn process_content_dedups_unicode_and_punycode_forms() {
+        let content = "0.0.0.0 münchen-ads.de\nxn--mnchen-ads-9db.de\n||MÜNCHEN-ADS.de^\n";
+        let set = process_content(content.as_bytes(), false, &ContentFilter::default());
+        assert_eq!(set, HashSet::from(["xn--mnchen-ads-9db.de".to_string()]));
+    }
+
     #[test]
     fn process_content_flattens_when_disabled() {
-        let set = process_content(b"||foo.com^\n*.bar.com\n", false);
+        let set = process_content(b"||foo.com^\n*.bar.com\n", false, &ContentFilter::default());
         assert!(set.contains("foo.com"));
         assert!(set.contains("bar.com"));
         assert!(!set.iter().any(|d| d.contains('*') || d.starts_with("||")));
     }
 
+    #[test]
+    fn process_content_star_wildcards_flatten_or_drop() {
+        let content = b"*.tracker.com\n0.0.0.0 ads.com\n";
+        let flattened = process_content(content, false, &ContentFilter::default());
+        assert!(flattened.contains("tracker.com"));
+        assert!(flattened.contains("ads.com"));
+
+        let drop = ContentFilter {
+            drop_star_wildcards: true,
+            ..Default::default()
+        };
+        let dropped = process_content(content, false, &drop);
+        assert_eq!(dropped, HashSet::from(["ads.com".to_string()]));
+
+        // `abp` sources keep wildcards regardless
+        let kept = process_content(content, true, &drop);
+        assert!(kept.contains("||tracker.com^"));
+    }
+
+    #[test]
+    fn process_content_skips_dnstype_rules_unless_kept() {
+        let content = b"||svcb.example.com^$dnstype=HTTPS\n||ads.example.com^\n";
+        let skipped = process_content(content, false, &ContentFilter::default());
+        assert_eq!(skipped, HashSet::from(["ads.example.com".to_string()]));
+
+        let keep = ContentFilter {
+            keep_dnstype_rules: true,
+            ..Default::default()
+        };
+        assert!(process_content(content, false, &keep).contains("svcb.example.com"));
+    }
+
+    #[test]
+    fn process_content_salvages_host_urls_when_enabled() {
+        let content = b"0.0.0.0 http://ads.example.com/banner.js\n0.0.0.0 track.example.com:8080\n";
+        assert!(process_content(content, false, &ContentFilter::default()).is_empty());
+
+        let salvage = ContentFilter {
+            salvage_host_urls: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            process_content(content, false, &salvage),
+            HashSet::from([
+                "ads.example.com".to_string(),
+                "track.example.com".to_string()
+            ])
+        );
+        assert_eq!(count_rejected_lines(content, false, &salvage), 0);
+    }
+
+    #[test]
+    fn process_content_keeps_only_sinkhole_hosts_lines() {
+        let filter = ContentFilter {
+            sinkhole_ips: Some(["0.0.0.0", "127.0.0.1", "::"].into()),
+            ..Default::default()
+        };
+        let content = b"0.0.0.0 ads.com\n127.0.0.1 track.com\n:: v6.com\n10.0.0.5 intranet.corp.com\nplain.com\n";
+        let set = process_content(content, false, &filter);
+        assert_eq!(set.len(), 4);
+        assert!(!set.contains("intranet.corp.com"));
+        assert!(set.contains("plain.com"));
+
+        let all = process_content(content, false, &ContentFilter::default());
+        assert!(all.contains("intranet.corp.com"));
+    }
+
+    #[test]
+    fn parallel_parse_matches_serial() {
+        let mut content = String::new();
+        for i in 0..5000 {
+            content.push_str(&format!(
+                "0.0.0.0 ads{i}.com\n*.w{i}.net\n10.0.0.1 lan{i}.corp\n"
+            ));
+            content.push_str(&format!("||d{}.org^$dnstype=A\n# comment\n", i % 100));
+        }
+        let filter = ContentFilter {
+            sinkhole_ips: Some(["0.0.0.0"].into()),
+            drop_star_wildcards: true,
+            ..Default::default()
+        };
+        for allow_wildcards in [false, true] {
+            let mut serial = HashSet::new();
+            for line in content.lines() {
+                add_line_keys(&mut serial, line, allow_wildcards, &filter);
+            }
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(4)
+                .build()
+                .unwrap();
+            let parallel =
+                pool.install(|| process_lines_parallel(&content, allow_wildcards, &filter));
+            assert_eq!(parallel, serial);
+            assert!(serial.contains("ads42.com"));
+            assert!(!serial.contains("lan42.corp"));
+        }
+    }
+
+    #[test]
+    fn one_thread_parses_serially() {
+        let one = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let four = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        assert!(!one.install(|| parse_in_parallel(PARALLEL_PARSE_MIN_BYTES)));
+        assert!(four.install(|| parse_in_parallel(PARALLEL_PARSE_MIN_BYTES)));
+        assert!(!four.install(|| parse_in_parallel(PARALLEL_PARSE_MIN_BYTES - 1)));
+    }
+
+    #[test]
+    fn master_union_skips_nsfw() {
+        let category_domains: HashMap<String, HashSet<String>> = [
+            ("ads", vec!["a.com", "shared.com"]),
+            ("tracking", vec!["t.com", "shared.com"]),
+            ("nsfw", vec!["n.com"]),
+        ]
+        .into_iter()
+        .map(|(cat, domains)| {
+            (
+                cat.to_string(),
+                domains.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+        assert_eq!(
+            master_union(&category_domains),
+            ["a.com", "shared.com", "t.com"].map(String::from).into()
+        );
+    }
+
+    #[test]
+    fn spooled_downloads_parse_like_in_memory_ones() {
+        let content = b"# list\r\n0.0.0.0 ads.com\r\n||x.com^\n^track[0-9]+\\.\nbad\xff.com\nnot a domain\nlast.org";
+        let dir = TempDir::new("spooled");
+        let path = dir.join("spooled.part");
+        std::fs::write(&path, content).unwrap();
+        let filter = ContentFilter::default();
+
+        let (hash, invalid) = scan_spooled(&path).unwrap();
+        assert_eq!(hash, sha256_hex(content));
+        assert_eq!(invalid, count_invalid_utf8(content));
+
+        let (domains, regexes) = process_spooled(&path, true, &filter).unwrap();
+        assert_eq!(domains, process_content(content, true, &filter));
+        assert_eq!(regexes, extract_regexes(content));
+        assert_eq!(
+            count_rejected_file_lines(&path, true, &filter).unwrap(),
+            count_rejected_lines(content, true, &filter)
+        );
+    }
+
+    #[test]
+    fn regex_lines_are_routed_out_of_domains() {
+        let content = b"0.0.0.0 ads.com\n(^|\\.)doubleclick\\.net$\n^track[0-9]+\\.\n||x.com^\n";
+        let domains = process_content(content, false, &ContentFilter::default());
+        assert_eq!(domains.len(), 2);
+        assert!(!domains.iter().any(|d| d.contains('\\')));
+
+        let regexes = extract_regexes(content);
+        assert_eq!(
+            regexes,
+            HashSet::from([
+                "(^|\\.)doubleclick\\.net$".to_string(),
+                "^track[0-9]+\\.".to_string(),
+            ])
+        );
+
+        let mut all = HashSet::new();
+        record_regexes(&mut all, &blocklist("adult", "nsfw"), regexes.clone());
+        assert!(all.is_empty());
+        record_regexes(&mut all, &blocklist("ads", "advertising"), regexes);
+        assert_eq!(all.len(), 2);
+    }
+
+    #[test]
+    fn count_rejected_lines_skips_comments_and_regexes() {
+        let content =
+            b"# header\n! abp comment\n\n0.0.0.0 ads.com\n^ads\\.\nnot a domain\n[Adblock Plus 2.0]\n";
+        assert_eq!(
+            count_rejected_lines(content, false, &ContentFilter::default()),
+            2
+        );
+    }
+
+    #[test]
+    fn count_rejected_lines_includes_filtered_lines() {
+        let content = b"10.0.0.5 intranet.example.com
+*.star.com
+ads.com$dnstype=AAAA
+0.0.0.0 ads.com
+";
+        let filter = ContentFilter {
+            sinkhole_ips: Some(HashSet::from(["0.0.0.0"])),
+            drop_star_wildcards: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            process_content(content, false, &filter),
+            HashSet::from(["ads.com".to_string()])
+        );
+        assert_eq!(count_rejected_lines(content, false, &filter), 3);
+    }
+
+    #[test]
+    fn ensure_writable_rejects_missing_directory() {
+        let dir = TempDir::new("writable");
+        assert!(ensure_writable(&dir).is_ok());
+        assert!(!dir
+            .join(format!(".write-test-{}", std::process::id()))
+            .exists());
+
+        let missing = dir.join("missing");
+        let err = ensure_writable(&missing).unwrap_err();
+        assert!(format!("{err:#}").contains("is not writable"));
+    }
+
+    #[test]
+    fn json_feeds_are_flattened_to_lines() {
+        let mut bl = blocklist("feed", "malicious");
+        let bare = br#"["a.com", "b.com", 3]"#;
+        assert_eq!(&*list_lines(&bl, bare), b"a.com\nb.com\n");
+
+        let wrapped = br#"{"domains": ["a.com"], "count": 1}"#;
+        assert_eq!(&*list_lines(&bl, wrapped), b"a.com\n");
+
+        // ABP headers start with `[` but aren't JSON
+        let abp = b"[Adblock Plus 2.0]\n||a.com^\n";
+        assert_eq!(&*list_lines(&bl, abp), abp);
+
+        bl.json_path = Some("data.1.hosts".to_string());
+        let nested = br#"{"data": [{"hosts": []}, {"hosts": ["x.com"]}]}"#;
+        assert_eq!(&*list_lines(&bl, nested), b"x.com\n");
+        assert_eq!(
+            process_content(&list_lines(&bl, nested), false, &ContentFilter::default()),
+            HashSet::from(["x.com".to_string()])
+        );
+    }
+
+    #[test]
+    fn seeded_shuffle_is_reproducible() {
+        let original: Vec<usize> = (0..50).collect();
+        let mut a = original.clone();
+        let mut b = original.clone();
+        shuffle_queue(&mut a, Some(7));
+        shuffle_queue(&mut b, Some(7));
+        assert_eq!(a, b);
+        assert_ne!(a, original);
+
+        a.sort_unstable();
+        assert_eq!(a, original);
+    }
+
+    #[test]
+    fn check_category_rejects_unknown_categories() {
+        let categories = ["advertising", "tracking"].map(String::from);
+        assert!(check_category("tracking", &categories).is_ok());
+        let err = check_category("trackin", &categories).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown blacklist category 'trackin'; expected one of: advertising, tracking"
+        );
+    }
+
+    #[test]
+    fn overlap_matrix_counts_shared_domains() {
+        let category_domains: HashMap<String, HashSet<String>> = [
+            ("tracking", ["a.com", "b.com", "c.com"].as_slice()),
+            ("ads", ["a.com", "b.com"].as_slice()),
+            ("malicious", ["c.com", "d.com"].as_slice()),
+        ]
+        .into_iter()
+        .map(|(cat, ds)| (cat.to_string(), ds.iter().map(|d| d.to_string()).collect()))
+        .collect();
+
+        let (names, matrix) = overlap_matrix(&category_domains);
+        assert_eq!(names, ["ads", "malicious", "tracking"]);
+        assert_eq!(matrix, [[2, 0, 2], [0, 2, 1], [2, 1, 3]]);
+    }
+
+    #[test]
+    fn trailing_dot_source_domains_are_whitelisted() {
+        let content = b"0.0.0.0 example.com.\nads.example.com.\n||cdn.tracker.net.^\nkeep.org.\n";
+        let domains = process_content(content, false, &ContentFilter::default());
+        assert!(domains.contains("example.com"));
+        assert!(!domains.iter().any(|d| d.ends_with('.')));
+
+        let whitelist = WhitelistManager::parse("example.com\n*.tracker.net\n", true);
+        let (kept, removed) = whitelist.filter_domains(&domains);
+        assert_eq!(removed.total(), 3);
+        assert_eq!(kept, HashSet::from(["keep.org".to_string()]));
+    }
+
+    #[test]
+    fn normalize_file_dedupes_and_sorts() {
+        let dir = TempDir::new("normalize");
+        let input = dir.join("in.txt");
+        let output = dir.join("out.txt");
+        std::fs::write(&input, "# junk\n0.0.0.0 B.com\nb.com.\n||x.com^\na.com\n").unwrap();
+
+        let count = normalize_file(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            LineEnding::Lf,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&output).unwrap();
+
+        assert_eq!(count, 3);
+        let body: Vec<&str> = written.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, ["", "0.0.0.0 a.com", "0.0.0.0 b.com", "||x.com^"]);
+    }
+
+    #[test]
+    fn sha256_hex_matches_known_digests() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn count_invalid_utf8_counts_replacements() {
+        assert_eq!(count_invalid_utf8(b"ads.example.com\n"), 0);
+        assert_eq!(count_invalid_utf8("m\u{fc}nchen.de".as_bytes()), 0);
+        assert_eq!(
+            count_invalid_utf8(b"ads\xff.example.com\n\xfe\xfetracker.com"),
+            3
+        );
+        let lossy = String::from_utf8_lossy(b"a\xffb\xc3");
+        assert_eq!(
+            lossy.matches('\u{fffd}').count(),
+            count_invalid_utf8(b"a\xffb\xc3")
+        );
+    }
+
+    fn blocklist(name: &str, category: &str) -> Blocklist {
+        Blocklist {
+            url: format!("https://example.com/{name}.txt"),
+            display_url: format!("https://example.com/{name}.txt"),
+            name: name.to_string(),
+            category: category.to_string(),
+            enabled: true,
+            allow_wildcards: false,
+            auto_categorize: false,
+            request: RequestSpec::default(),
+            sha256: None,
+            json_path: None,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn missing_local_file_clears_validators() {
+        let dir = TempDir::new("validators");
+        let present = dir.join("cached.txt");
+        let missing = dir.join("missing.txt");
+        std::fs::write(&present, "a.com\n").unwrap();
+
+        let mut bl = blocklist("cached", "advertising");
+        bl.etag = Some("\"abc\"".to_string());
+        bl.last_modified = Some("Mon, 01 Jan 2024 00:00:00 GMT".to_string());
+
+        // The server would answer 304, but the file is still on disk
+        assert!(!clear_validators_if_missing(&mut bl, &present));
+        assert!(bl.etag.is_some());
+
+        // Simulated 304 with the file deleted: validators are dropped
+        assert!(clear_validators_if_missing(&mut bl, &missing));
+        assert!(bl.etag.is_none() && bl.last_modified.is_none());
+        assert!(!clear_validators_if_missing(&mut bl, &missing));
+    }
+
+    #[test]
+    fn add_list_domains_counts_before_dedup() {
+        let mut category_domains = HashMap::new();
+        let mut listed = 0;
+        let mut auto_counts = None;
+        let first: HashSet<String> = ["a.com", "b.com"].map(String::from).into();
+        let second: HashSet<String> = ["b.com", "c.com"].map(String::from).into();
+        let adult: HashSet<String> = ["d.com"].map(String::from).into();
+
+        add_list_domains(
+            &mut category_domains,
+            &mut listed,
+            &mut auto_counts,
+            &blocklist("one", "ads"),
+            first,
+        );
+        add_list_domains(
+            &mut category_domains,
+            &mut listed,
+            &mut auto_counts,
+            &blocklist("two", "ads"),
+            second,
+        );
+        add_list_domains(
+            &mut category_domains,
+            &mut listed,
+            &mut auto_counts,
+            &blocklist("x", "nsfw"),
+            adult,
+        );
+
+        assert_eq!(listed, 4);
+        assert_eq!(category_domains["ads"].len(), 3);
+        assert_eq!(category_domains["nsfw"].len(), 1);
+    }
+
+    #[test]
+    fn add_list_domains_auto_categorizes_marked_lists() {
+        let mut category_domains = HashMap::new();
+        let mut listed = 0;
+        let mut auto_counts = Some(HashMap::new());
+        let mut mega = blocklist("mega", "comprehensive");
+        mega.auto_categorize = true;
+        let domains: HashSet<String> = ["ads.a.com", "metrics.b.com", "plain.c.com"]
+            .map(String::from)
+            .into();
+
+        add_list_domains(
+            &mut category_domains,
+            &mut listed,
+            &mut auto_counts,
+            &mega,
+            domains.clone(),
+        );
+        assert!(category_domains["advertising"].contains("ads.a.com"));
+        assert!(category_domains["tracking"].contains("metrics.b.com"));
+        assert!(category_domains["comprehensive"].contains("plain.c.com"));
+        let counts = auto_counts.as_ref().unwrap();
+        assert_eq!((counts["advertising"], counts["unclassified"]), (1, 1));
+
+        // Lists without `auto` keep their category
+        let mut plain = HashMap::new();
+        add_list_domains(
+            &mut plain,
+            &mut listed,
+            &mut auto_counts,
+            &blocklist("p", "suspicious"),
+            domains,
+        );
+        assert_eq!(plain.keys().collect::<Vec<_>>(), ["suspicious"]);
+    }
+
+    #[test]
+    fn record_sources_keeps_first_writer_and_skips_nsfw() {
+        let mut sources = Some(HashMap::new());
+        let first: HashSet<String> = ["a.com", "b.com"].map(String::from).into();
+        let second: HashSet<String> = ["b.com", "c.com"].map(String::from).into();
+        let adult: HashSet<String> = ["d.com"].map(String::from).into();
+
+        record_sources(&mut sources, 0, &blocklist("first", "advertising"), &first);
+        record_sources(&mut sources, 1, &blocklist("second", "tracking"), &second);
+        record_sources(&mut sources, 2, &blocklist("adult", "nsfw"), &adult);
+
+        let sources = sources.unwrap();
+        assert_eq!(sources.len(), 3);
+        assert_eq!(&*sources["a.com"].1, "first");
+        assert_eq!(&*sources["b.com"].1, "first");
+        assert_eq!(&*sources["c.com"].1, "second");
+        assert!(!sources.contains_key("d.com"));
+
+        let mut disabled = None;
+        record_sources(&mut disabled, 0, &blocklist("first", "advertising"), &first);
+        assert!(disabled.is_none());
+    }
+
+    #[test]
+    fn record_sources_prefers_config_order_over_completion_order() {
+        let mut sources = Some(HashMap::new());
+        let shared: HashSet<String> = ["a.com"].map(String::from).into();
+
+        // The later list finishes downloading first
+        record_sources(&mut sources, 3, &blocklist("later", "advertising"), &shared);
+        record_sources(&mut sources, 1, &blocklist("earlier", "tracking"), &shared);
+        record_sources(&mut sources, 2, &blocklist("middle", "tracking"), &shared);
+
+        assert_eq!(&*sources.unwrap()["a.com"].1, "earlier");
+    }
+
     #[test]
     fn format_blocklist_line_handles_both_forms() {
-        assert_eq!(format_blocklist_line("foo.com"), "0.0.0.0 foo.com");
-        assert_eq!(format_blocklist_line("||foo.com^"), "||foo.com^");
+        assert_eq!(
+            format_blocklist_line("foo.com", "0.0.0.0"),
+            "0.0.0.0 foo.com"
+        );
+        assert_eq!(format_blocklist_line("||foo.com^", "0.0.0.0"), "||foo.com^");
+    }
+
+    #[test]
+    fn write_blocklist_file_cycles_hosts_ips() {
+        let dir = TempDir::new("rr");
+        let path = dir.join("rr.txt");
+        let domains: HashSet<String> = ["a.com", "b.com", "c.com"].map(String::from).into();
+        let ips: Vec<IpAddr> = ["10.0.0.1", "10.0.0.2"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        write_blocklist_file(
+            &path,
+            &domains,
+            None,
+            OutputFormat::Hosts,
+            &ips,
+            LineEnding::Lf,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+
+        let body: Vec<&str> = written.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            body,
+            ["", "10.0.0.1 a.com", "10.0.0.2 b.com", "10.0.0.1 c.com"]
+        );
+    }
+
+    #[test]
+    fn bloom_filter_finds_wildcard_entries_by_domain() {
+        let keys: HashSet<String> = ["||a.com^", "b.com"].map(String::from).into();
+        let bloom = bloom_filter(&keys, 0.001);
+        assert!(bloom.contains("a.com"));
+        assert!(bloom.contains("b.com"));
+    }
+
+    #[test]
+    fn write_blocklist_file_rpz_zone() {
+        let dir = TempDir::new("rpz");
+        let path = dir.join("rpz.txt");
+        let domains: HashSet<String> = ["b.com", "||a.com^"].map(String::from).into();
+        write_blocklist_file(
+            &path,
+            &domains,
+            None,
+            OutputFormat::Rpz,
+            &[],
+            LineEnding::Lf,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let reread = load_written_names(&path, OutputFormat::Rpz).unwrap();
+
+        let records: Vec<&str> = written
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with(';'))
+            .collect();
+        assert_eq!(records[0], "$TTL 2h");
+        assert!(records[1].starts_with("@ IN SOA localhost. root.localhost. ("));
+        assert_eq!(
+            records[3..],
+            [
+                "a.com CNAME .",
+                "*.a.com CNAME .",
+                "b.com CNAME .",
+                "*.b.com CNAME .",
+            ]
+        );
+        assert_eq!(reread, ["a.com", "b.com"].map(String::from).into());
+        assert!(written.contains("; Total domains: 2\n"));
+    }
+
+    #[test]
+    fn write_blocklist_file_dnsmasq_collapses_subdomains() {
+        let dir = TempDir::new("dnsmasq");
+        let path = dir.join("dnsmasq.txt");
+        let domains: HashSet<String> = ["ads.a.com", "||a.com^", "a.com", "x.b.com", "xb.com"]
+            .map(String::from)
+            .into();
+        let ips: Vec<IpAddr> = vec!["::".parse().unwrap()];
+        write_blocklist_file(
+            &path,
+            &domains,
+            None,
+            OutputFormat::Dnsmasq,
+            &ips,
+            LineEnding::Lf,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let reread = load_written_names(&path, OutputFormat::Dnsmasq).unwrap();
+
+        let body: Vec<&str> = written
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .collect();
+        assert_eq!(
+            body,
+            [
+                "address=/a.com/::",
+                "address=/x.b.com/::",
+                "address=/xb.com/::"
+            ]
+        );
+        assert_eq!(reread.len(), 3);
+        assert!(written.contains("# Total domains: 3\n"));
+    }
+
+    #[test]
+    fn write_blocklist_file_domains_has_no_prefix() {
+        let dir = TempDir::new("bare");
+        let path = dir.join("bare.txt");
+        let domains: HashSet<String> = ["b.com", "||a.com^", "a.com"].map(String::from).into();
+        write_blocklist_file(
+            &path,
+            &domains,
+            None,
+            OutputFormat::Domains,
+            &[],
+            LineEnding::Lf,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+
+        let body: Vec<&str> = written.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, ["", "a.com", "b.com"]);
+        assert!(written.contains("# Total domains: 2\n"));
+    }
+
+    #[test]
+    fn write_blocklist_file_adguard_filter_list() {
+        let dir = TempDir::new("adguard");
+        let path = dir.join("adguard.txt");
+        let domains: HashSet<String> = ["b.com", "||a.com^", "a.com"].map(String::from).into();
+        write_blocklist_file(
+            &path,
+            &domains,
+            Some("Advertising"),
+            OutputFormat::Adguard,
+            &[],
+            LineEnding::Lf,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let reread = load_written_names(&path, OutputFormat::Adguard).unwrap();
+
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines[0], "! Title: Pi-hole Advertising Blocklist");
+        assert!(lines[1].starts_with("! Version: "));
+        assert!(lines[2].starts_with("! Last modified: "));
+        assert_eq!(lines[3], "! Total domains: 2");
+        assert_eq!(lines[4..], ["!", "||a.com^", "||b.com^"]);
+        assert_eq!(reread, ["a.com", "b.com"].map(String::from).into());
+    }
+
+    #[test]
+    fn gzipped_hosts_data_is_decompressed_before_parsing() {
+        let hosts = b"0.0.0.0 ads.example.com\n0.0.0.0 track.example.com\n";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(hosts).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let content = gunzip_if_compressed(gzipped).unwrap();
+        assert_eq!(content, hosts);
+        let domains = process_content(&content, false, &ContentFilter::default());
+        assert_eq!(domains.len(), 2);
+        assert!(domains.contains("track.example.com"));
+
+        assert_eq!(gunzip_if_compressed(hosts.to_vec()).unwrap(), hosts);
+        assert!(gunzip_if_compressed(vec![0x1f, 0x8b, 0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn looks_like_html_checks_type_and_opening_tag() {
+        assert!(looks_like_html(
+            Some("text/html; charset=utf-8"),
+            b"0.0.0.0 a.com"
+        ));
+        assert!(looks_like_html(
+            None,
+            b"\n  <!DOCTYPE html><title>Moved</title>"
+        ));
+        assert!(looks_like_html(None, b"\xEF\xBB\xBF<HTML>"));
+        assert!(!looks_like_html(
+            Some("text/plain"),
+            b"0.0.0.0 a.com\n<html>"
+        ));
+        assert!(!looks_like_html(None, b"# <html> mentioned in a comment"));
+        assert!(!looks_like_html(None, b""));
+    }
+
+    #[test]
+    fn count_change_flags_sharp_shrinks() {
+        let change = |previous, current| CountChange {
+            name: String::new(),
+            previous,
+            current,
+        };
+        assert!(change(Some(10_000), 0).shrank_sharply());
+        assert!(change(Some(10_000), 5_000).shrank_sharply());
+        assert!(!change(Some(10_000), 5_001).shrank_sharply());
+        assert!(!change(Some(10_000), 20_000).shrank_sharply());
+        assert!(!change(Some(0), 0).shrank_sharply());
+        assert!(!change(None, 0).shrank_sharply());
+        assert_eq!(format_delta(-1234), "-1,234");
+        assert_eq!(format_delta(5), "+5");
+        assert_eq!(format_delta(0), "0");
+    }
+
+    #[test]
+    fn content_cache_remembers_the_first_list_per_content() {
+        let mut cache = ContentCache::default();
+        let one = ContentCache::key("h1".into(), &blocklist("one", "ads"));
+        assert_eq!(cache.first(&one), None);
+        cache.record(one.clone(), "one");
+
+        let two = ContentCache::key("h1".into(), &blocklist("two", "ads"));
+        assert_eq!(cache.first(&two), Some("one"));
+        cache.record(two.clone(), "two");
+        assert_eq!(cache.first(&two), Some("one"));
+
+        // Same bytes parsed with different flags are not interchangeable
+        let mut abp = blocklist("three", "ads");
+        abp.allow_wildcards = true;
+        assert_eq!(cache.first(&ContentCache::key("h1".into(), &abp)), None);
+    }
+
+    #[test]
+    fn downloaded_within_compares_against_the_interval() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-05-02T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let six_hours = Duration::from_secs(6 * 3600);
+        assert!(downloaded_within(
+            "2024-05-02T09:00:00+02:00",
+            six_hours,
+            now
+        ));
+        assert!(!downloaded_within("2024-05-02T05:59:59Z", six_hours, now));
+        assert!(!downloaded_within("2024-05-02T13:00:00Z", six_hours, now));
+        assert!(!downloaded_within("yesterday", six_hours, now));
+    }
+
+    #[test]
+    fn overlap_pairs_ranks_shared_domains_per_pair() {
+        let category_domains: HashMap<String, HashSet<String>> = [
+            ("ads", vec!["a.com", "b.com", "c.com", "d.com"]),
+            ("tracking", vec!["b.com", "c.com", "t.com"]),
+            ("malicious", vec!["m.com"]),
+            ("nsfw", vec!["d.com"]),
+        ]
+        .into_iter()
+        .map(|(cat, domains)| {
+            (
+                cat.to_string(),
+                domains.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+
+        let (names, matrix) = overlap_matrix(&category_domains);
+        let overlap = overlap_pairs(&names, &matrix);
+        let pairs: Vec<(&str, &str, usize)> = overlap
+            .iter()
+            .map(|o| (o.first, o.second, o.shared))
+            .collect();
+        assert_eq!(pairs, [("ads", "tracking", 2), ("ads", "nsfw", 1)]);
+        // Percent of the smaller category: 2 of tracking's 3, all of nsfw
+        assert!((overlap[0].percent - 66.67).abs() < 0.01);
+        assert_eq!(overlap[1].percent, 100.0);
+    }
+
+    #[test]
+    fn combined_annotated_sorts_by_category_then_domain() {
+        let dir = TempDir::new("annotated");
+        let path = dir.join("annotated.txt");
+        let categories: BTreeMap<String, HashSet<String>> = [
+            ("tracking", vec!["t.com", "shared.com"]),
+            ("advertising", vec!["z.com", "shared.com"]),
+        ]
+        .into_iter()
+        .map(|(cat, domains)| {
+            (
+                cat.to_string(),
+                domains.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+        write_combined_annotated(&path, &categories, LineEnding::Lf).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+
+        let body: Vec<&str> = written
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .collect();
+        assert_eq!(
+            body,
+            [
+                "shared.com # [advertising]",
+                "z.com # [advertising]",
+                "shared.com # [tracking]",
+                "t.com # [tracking]",
+            ]
+        );
+    }
+
+    #[test]
+    fn collapse_subdomains_drops_entries_under_a_listed_parent() {
+        let domains: HashSet<String> = [
+            "example.com",
+            "ads.example.com",
+            "a.b.example.com",
+            "||tracker.net^",
+            "cdn.tracker.net",
+            "||x.cdn.tracker.net^",
+            "other.org",
+            "example.com.evil.org",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+        let mut kept: Vec<String> = collapse_subdomains(&domains).into_iter().collect();
+        kept.sort();
+        assert_eq!(
+            kept,
+            [
+                "example.com",
+                "example.com.evil.org",
+                "other.org",
+                "||tracker.net^"
+            ]
+        );
     }
 
     #[test]
@@ -1,46 +1,133 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 const PROGRESS_FILE: &str = "download_progress.json";
+/// Number of recent domain counts kept per list for anomaly detection.
+const HISTORY_LEN: usize = 10;
+/// Minimum history before a new count is judged against it.
+const MIN_HISTORY: usize = 3;
+/// How many standard deviations from the recent mean count as anomalous.
+const ANOMALY_SIGMAS: f64 = 2.0;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProgressEntry {
     pub etag: Option<String>,
     pub last_modified: Option<String>,
+    /// SHA-256 of the last processed raw body, so an identical body that
+    /// arrives without validators isn't parsed again.
+    #[serde(default)]
+    pub content_hash: Option<String>,
     pub domain_count: usize,
     pub last_download: String,
+    /// Domain counts of the most recent downloads, oldest first.
+    #[serde(default)]
+    pub history: Vec<usize>,
+}
+
+impl ProgressEntry {
+    /// Mean and standard deviation of the recent counts if `count` falls more
+    /// than `ANOMALY_SIGMAS` deviations away from them, `None` otherwise.
+    /// Deviations under 1% of the mean are ignored so a perfectly stable
+    /// history doesn't flag every small change.
+    pub fn count_anomaly(&self, count: usize) -> Option<(f64, f64)> {
+        if self.history.len() < MIN_HISTORY {
+            return None;
+        }
+        let n = self.history.len() as f64;
+        let mean = self.history.iter().sum::<usize>() as f64 / n;
+        let variance = self
+            .history
+            .iter()
+            .map(|&c| (c as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let std_dev = variance.sqrt();
+
+        let deviation = (count as f64 - mean).abs();
+        (deviation > ANOMALY_SIGMAS * std_dev && deviation > mean * 0.01).then_some((mean, std_dev))
+    }
 }
 
 pub struct ProgressTracker {
     entries: HashMap<String, ProgressEntry>,
+    path: PathBuf,
+    /// Save after every `update` instead of on `flush`.
+    sync_each: bool,
+    /// Updates not yet written to disk.
+    dirty: bool,
+}
+
+/// The `.bak` copy kept next to `path`.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+fn read_entries(path: &Path) -> anyhow::Result<HashMap<String, ProgressEntry>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
 }
 
 impl ProgressTracker {
     pub fn load() -> Self {
-        let entries = if Path::new(PROGRESS_FILE).exists() {
-            match std::fs::read_to_string(PROGRESS_FILE) {
-                Ok(content) => match serde_json::from_str(&content) {
-                    Ok(map) => {
-                        let map: HashMap<String, ProgressEntry> = map;
-                        log::debug!("Loaded progress for {} lists", map.len());
-                        map
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to parse progress file: {e}");
-                        HashMap::new()
-                    }
-                },
+        Self::load_from(Path::new(PROGRESS_FILE))
+    }
+
+    /// Load progress from `path`, falling back to its `.bak` copy if the file
+    /// is missing or unreadable (e.g. truncated by a crash).
+    pub(crate) fn load_from(path: &Path) -> Self {
+        let backup = backup_path(path);
+        let entries = if path.exists() {
+            match read_entries(path) {
+                Ok(map) => {
+                    log::debug!("Loaded progress for {} lists", map.len());
+                    Some(map)
+                }
                 Err(e) => {
-                    log::warn!("Failed to read progress file: {e}");
-                    HashMap::new()
+                    log::warn!("Failed to load progress file: {e}");
+                    None
                 }
             }
         } else {
-            HashMap::new()
+            None
         };
+        let entries = entries.or_else(|| {
+            if !backup.exists() {
+                return None;
+            }
+            match read_entries(&backup) {
+                Ok(map) => {
+                    log::warn!(
+                        "Recovered progress for {} lists from {}",
+                        map.len(),
+                        backup.display()
+                    );
+                    Some(map)
+                }
+                Err(e) => {
+                    log::warn!("Failed to load progress backup: {e}");
+                    None
+                }
+            }
+        });
 
-        Self { entries }
+        Self {
+            entries: entries.unwrap_or_default(),
+            path: path.to_path_buf(),
+            sync_each: false,
+            dirty: false,
+        }
+    }
+
+    /// Save on every `update` (`--progress-sync-each`), so a crash mid-run
+    /// loses nothing, at the cost of rewriting the file once per list.
+    pub fn with_sync_each(mut self, sync_each: bool) -> Self {
+        self.sync_each = sync_each;
+        self
     }
 
     pub fn get(&self, name: &str) -> Option<&ProgressEntry> {
@@ -52,28 +139,205 @@ impl ProgressTracker {
         name: &str,
         etag: Option<&str>,
         last_modified: Option<&str>,
+        content_hash: Option<&str>,
         domain_count: usize,
     ) {
+        let mut history = self
+            .entries
+            .remove(name)
+            .map(|e| e.history)
+            .unwrap_or_default();
+        history.push(domain_count);
+        if history.len() > HISTORY_LEN {
+            history.drain(..history.len() - HISTORY_LEN);
+        }
+
         self.entries.insert(
             name.to_string(),
             ProgressEntry {
                 etag: etag.map(String::from),
                 last_modified: last_modified.map(String::from),
+                content_hash: content_hash.map(String::from),
                 domain_count,
                 last_download: chrono::Local::now().to_rfc3339(),
+                history,
             },
         );
-        self.save();
+        self.dirty = true;
+        if self.sync_each {
+            self.flush();
+        }
+    }
+
+    /// Replace a list's cached validators without recording a new download,
+    /// for a body that turned out identical to the one already processed.
+    pub fn set_validators(&mut self, name: &str, etag: Option<&str>, last_modified: Option<&str>) {
+        let Some(entry) = self.entries.get_mut(name) else {
+            return;
+        };
+        if entry.etag.as_deref() == etag && entry.last_modified.as_deref() == last_modified {
+            return;
+        }
+        entry.etag = etag.map(String::from);
+        entry.last_modified = last_modified.map(String::from);
+        self.dirty = true;
+        if self.sync_each {
+            self.flush();
+        }
+    }
+
+    /// Write pending updates to disk, if there are any.
+    pub fn flush(&mut self) {
+        if self.dirty {
+            self.save();
+            self.dirty = false;
+        }
     }
 
     fn save(&self) {
         match serde_json::to_string_pretty(&self.entries) {
             Ok(json) => {
-                if let Err(e) = std::fs::write(PROGRESS_FILE, json) {
+                if let Err(e) = self.write_atomically(&json) {
                     log::error!("Failed to save progress: {e}");
                 }
             }
             Err(e) => log::error!("Failed to serialize progress: {e}"),
         }
     }
+
+    /// Write `json` to a temporary file beside the progress file and rename it
+    /// into place, so a crash leaves either the old or the new file, never a
+    /// truncated one. The previous file is copied to `.bak` first.
+    fn write_atomically(&self, json: &str) -> std::io::Result<()> {
+        let mut tmp_name = self.path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+
+        if self.path.exists() {
+            std::fs::copy(&self.path, backup_path(&self.path))?;
+        }
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Updates are only written by `flush`, so save them if the run ends early.
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn entry(history: &[usize]) -> ProgressEntry {
+        ProgressEntry {
+            etag: None,
+            last_modified: None,
+            content_hash: None,
+            domain_count: history.last().copied().unwrap_or(0),
+            last_download: String::new(),
+            history: history.to_vec(),
+        }
+    }
+
+    #[test]
+    fn count_anomaly_needs_history() {
+        assert_eq!(entry(&[1000, 1000]).count_anomaly(10), None);
+    }
+
+    #[test]
+    fn count_anomaly_flags_drops_and_spikes() {
+        let e = entry(&[10_000, 10_200, 9_900, 10_100, 10_050]);
+        assert_eq!(e.count_anomaly(10_080), None);
+        assert!(e.count_anomaly(4_000).is_some());
+        assert!(e.count_anomaly(25_000).is_some());
+    }
+
+    #[test]
+    fn count_anomaly_ignores_tiny_changes_on_flat_history() {
+        let e = entry(&[5_000, 5_000, 5_000]);
+        assert_eq!(e.count_anomaly(5_010), None);
+        assert!(e.count_anomaly(4_000).is_some());
+    }
+
+    #[test]
+    fn truncated_progress_file_recovers_from_backup() {
+        let dir = TempDir::new("progress");
+        let path = dir.join("progress.json");
+
+        let mut tracker = ProgressTracker::load_from(&path);
+        tracker.update("ads", Some("\"v1\""), None, Some("abc"), 100);
+        tracker.flush();
+        tracker.update("ads", Some("\"v2\""), None, Some("def"), 120);
+        tracker.flush();
+        assert!(!dir.join("progress.json.tmp").exists());
+
+        // Simulate a crash that left the primary file cut short
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &json[..json.len() / 2]).unwrap();
+
+        let recovered = ProgressTracker::load_from(&path);
+        let entry = recovered.get("ads").unwrap();
+        assert_eq!(entry.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(entry.content_hash.as_deref(), Some("abc"));
+        assert_eq!(entry.domain_count, 100);
+    }
+
+    #[test]
+    fn updates_are_written_on_flush_or_drop() {
+        let dir = TempDir::new("flush");
+        let path = dir.join("progress.json");
+
+        let mut tracker = ProgressTracker::load_from(&path);
+        tracker.update("ads", None, None, None, 1);
+        tracker.update("track", None, None, None, 2);
+        assert!(!path.exists());
+        tracker.flush();
+        assert_eq!(ProgressTracker::load_from(&path).entries.len(), 2);
+
+        tracker.update("more", None, None, None, 3);
+        drop(tracker);
+        assert_eq!(ProgressTracker::load_from(&path).entries.len(), 3);
+
+        let mut each = ProgressTracker::load_from(&path).with_sync_each(true);
+        each.update("last", None, None, None, 4);
+        assert_eq!(ProgressTracker::load_from(&path).entries.len(), 4);
+    }
+
+    #[test]
+    fn set_validators_keeps_count_and_history() {
+        let dir = TempDir::new("validators");
+        let path = dir.join("progress.json");
+        let mut tracker = ProgressTracker::load_from(&path);
+        tracker.update("ads", Some("\"v1\""), None, Some("abc"), 100);
+        tracker.dirty = false;
+
+        tracker.set_validators("ads", Some("\"v1\""), None);
+        assert!(!tracker.dirty);
+        tracker.set_validators("ads", Some("\"v2\""), Some("Tue"));
+        tracker.set_validators("missing", Some("\"v9\""), None);
+        tracker.dirty = false;
+
+        let entry = tracker.get("ads").unwrap();
+        assert_eq!(entry.etag.as_deref(), Some("\"v2\""));
+        assert_eq!(entry.last_modified.as_deref(), Some("Tue"));
+        assert_eq!(entry.content_hash.as_deref(), Some("abc"));
+        assert_eq!(entry.history, [100]);
+        assert!(tracker.get("missing").is_none());
+    }
+
+    #[test]
+    fn old_progress_files_load_without_history() {
+        let json = r#"{"etag":null,"last_modified":null,"domain_count":5,"last_download":"x"}"#;
+        let e: ProgressEntry = serde_json::from_str(json).unwrap();
+        assert!(e.history.is_empty());
+        assert_eq!(e.content_hash, None);
+    }
 }
@@ -0,0 +1,37 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A scratch directory under the system temp dir, removed with everything in
+/// it when dropped, so a failing assertion doesn't leave files behind.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create `pihole-{name}-{pid}`, emptied first in case an earlier run
+    /// with the same pid was killed. `name` must be unique per test.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("pihole-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,42 +1,184 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use log::{debug, info, warn};
-use regex::Regex;
-use std::collections::HashSet;
+use rayon::prelude::*;
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
+use std::sync::Mutex;
+
+use crate::domain::{normalize_domain, parent_domains, validate_domain};
+use crate::output::{create_output, LineEnding};
+
+const REGEX_FLAGS: &str = "imsxU";
+
+/// Lazy DFA cache for the whitelist pattern set. The default (2 MiB) is
+/// exhausted by a few thousand wildcards, which drops matching to the much
+/// slower NFA for every domain.
+const PATTERN_SET_DFA_LIMIT: usize = 64 << 20;
+
+/// Default number of subdomains one entry may remove before it is reported as broad.
+pub const DEFAULT_BROAD_ENTRY_THRESHOLD: usize = 1000;
+
+/// Split a `/pattern/flags` whitelist entry into its pattern and flag characters.
+/// Text after the last `/` is only flags when every character is one of
+/// [`REGEX_FLAGS`]; otherwise the entry has no closing `/` and is not a regex.
+fn split_regex_entry(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('/')?;
+    let end = rest.rfind('/')?;
+    let (pattern, flags) = (&rest[..end], &rest[end + 1..]);
+    if pattern.is_empty() || !flags.chars().all(|c| REGEX_FLAGS.contains(c)) {
+        return None;
+    }
+    Some((pattern, flags))
+}
+
+/// Validate a regex entry with its flags applied and return it as a group
+/// suitable for the combined pattern (flags become an inline `(?flags:...)`).
+fn compile_regex_entry(pattern: &str, flags: &str) -> Result<String, String> {
+    let mut builder = RegexBuilder::new(pattern);
+    for flag in flags.chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'x' => builder.ignore_whitespace(true),
+            'U' => builder.swap_greed(true),
+            other => return Err(format!("unknown flag '{other}' (supported: {REGEX_FLAGS})")),
+        };
+    }
+    builder.build().map_err(|e| e.to_string())?;
+
+    if flags.is_empty() {
+        Ok(format!("(?:{pattern})"))
+    } else {
+        Ok(format!("(?{flags}:{pattern})"))
+    }
+}
+
+/// Anchored regex for a wildcard entry. A leading `*.` also matches the bare
+/// domain, so `*.example.com` covers `example.com` as well as its subdomains;
+/// any other `*` matches any run of characters.
+fn wildcard_regex(entry: &str) -> String {
+    let (prefix, rest) = match entry.strip_prefix("*.") {
+        Some(rest) => (r"(?:.*\.)?", rest),
+        None => ("", entry),
+    };
+    format!("^{prefix}{}$", rest.replace('.', r"\.").replace('*', ".*"))
+}
+
+/// 1-based numbers of the lines in `bytes` that are not valid UTF-8.
+fn invalid_utf8_lines(bytes: &[u8]) -> Vec<usize> {
+    bytes
+        .split(|&b| b == b'\n')
+        .enumerate()
+        .filter(|(_, line)| std::str::from_utf8(line).is_err())
+        .map(|(i, _)| i + 1)
+        .collect()
+}
+
+/// Domains removed by `filter_domains`, by the kind of entry that matched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WhitelistMatches {
+    pub exact: usize,
+    pub subdomain: usize,
+    /// Wildcard and regex entries.
+    pub pattern: usize,
+}
+
+impl WhitelistMatches {
+    pub fn total(&self) -> usize {
+        self.exact + self.subdomain + self.pattern
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            exact: self.exact + other.exact,
+            subdomain: self.subdomain + other.subdomain,
+            pattern: self.pattern + other.pattern,
+        }
+    }
+}
 
-use crate::domain::{normalize_domain, validate_domain};
+#[derive(Debug, Clone, Copy)]
+enum MatchKind {
+    Exact,
+    Subdomain,
+    Pattern,
+}
 
 pub struct WhitelistManager {
     exact_domains: HashSet<String>,
-    combined_pattern: Option<Regex>,
+    /// `=domain` entries: match only the domain itself, never its subdomains.
+    exact_only_domains: HashSet<String>,
+    /// Every wildcard/regex entry, compiled individually but matched in one
+    /// pass. `None` if the set exceeds the regex size limit, in which case
+    /// `patterns` are tried one by one.
+    pattern_set: Option<RegexSet>,
+    /// Each wildcard/regex entry as written, with its own regex, so removals
+    /// can be traced to a rule.
+    patterns: Vec<(String, Regex)>,
     enable_subdomain: bool,
+    invalid_entries: usize,
+    /// Warn when one entry's subdomain matching removes more than this (0 = off).
+    broad_entry_threshold: usize,
+    /// Entries already reported as broad, so per-category passes don't repeat them.
+    warned_broad_entries: Mutex<HashSet<String>>,
 }
 
 impl WhitelistManager {
     pub fn load(whitelist_file: &str, enable_subdomain: bool) -> Self {
-        let mut manager = Self {
-            exact_domains: HashSet::new(),
-            combined_pattern: None,
-            enable_subdomain,
-        };
-
         if !Path::new(whitelist_file).exists() {
             debug!("Whitelist file not found: {whitelist_file}");
-            return manager;
+            return Self::parse("", enable_subdomain);
         }
 
-        let content = match std::fs::read_to_string(whitelist_file) {
-            Ok(c) => c,
+        let bytes = match std::fs::read(whitelist_file) {
+            Ok(b) => b,
             Err(e) => {
                 log::error!("Failed to load whitelist: {e}");
-                return manager;
+                return Self::parse("", enable_subdomain);
             }
         };
 
+        Self::from_bytes(&bytes, enable_subdomain)
+    }
+
+    /// Like `load`, but a missing or unreadable file is an error instead of an
+    /// empty whitelist (`--require-whitelist`).
+    pub fn load_required(whitelist_file: &str, enable_subdomain: bool) -> Result<Self> {
+        if !Path::new(whitelist_file).exists() {
+            bail!("Whitelist file not found: {whitelist_file}");
+        }
+        let bytes = std::fs::read(whitelist_file)
+            .with_context(|| format!("Failed to load whitelist {whitelist_file}"))?;
+        Ok(Self::from_bytes(&bytes, enable_subdomain))
+    }
+
+    fn from_bytes(bytes: &[u8], enable_subdomain: bool) -> Self {
+        for line_num in invalid_utf8_lines(bytes) {
+            warn!("Invalid UTF-8 on whitelist line {line_num}, replaced with U+FFFD");
+        }
+
+        Self::parse(&String::from_utf8_lossy(bytes), enable_subdomain)
+    }
+
+    /// Build a whitelist from the contents of a whitelist file.
+    pub fn parse(content: &str, enable_subdomain: bool) -> Self {
+        let mut manager = Self {
+            exact_domains: HashSet::new(),
+            exact_only_domains: HashSet::new(),
+            pattern_set: None,
+            patterns: Vec::new(),
+            enable_subdomain,
+            invalid_entries: 0,
+            broad_entry_threshold: DEFAULT_BROAD_ENTRY_THRESHOLD,
+            warned_broad_entries: Mutex::new(HashSet::new()),
+        };
+
         let mut exact_count = 0usize;
         let mut wildcard_count = 0usize;
         let mut regex_count = 0usize;
-        let mut all_patterns: Vec<String> = Vec::new();
 
         for (line_num, line) in content.lines().enumerate() {
             let line = if let Some(pos) = line.find('#') {
@@ -49,36 +191,58 @@ impl WhitelistManager {
                 continue;
             }
 
-            // Regex pattern: /pattern/
-            if line.starts_with('/') && line.ends_with('/') && line.len() > 2 {
-                let pattern = &line[1..line.len() - 1];
-                match Regex::new(pattern) {
-                    Ok(_) => {
-                        all_patterns.push(format!("(?:{pattern})"));
+            // Regex pattern: /pattern/ or /pattern/flags
+            if let Some((pattern, flags)) = split_regex_entry(line) {
+                match compile_regex_entry(pattern, flags)
+                    .and_then(|grouped| Regex::new(&grouped).map_err(|e| e.to_string()))
+                {
+                    Ok(re) => {
+                        manager.patterns.push((line.to_string(), re));
                         regex_count += 1;
                     }
                     Err(e) => {
-                        warn!("Invalid regex on line {}: {pattern} - {e}", line_num + 1);
+                        warn!("Invalid regex on line {}: {line} - {e}", line_num + 1);
+                        manager.invalid_entries += 1;
                     }
                 }
                 continue;
             }
+            if line.starts_with('/') {
+                warn!(
+                    "Invalid regex on line {}: {line} - expected /pattern/ or /pattern/flags \
+                     (flags: {REGEX_FLAGS})",
+                    line_num + 1
+                );
+                manager.invalid_entries += 1;
+                continue;
+            }
 
             // Wildcard pattern: contains *
             if line.contains('*') {
-                let regex_pattern = format!("^{}$", line.replace('.', r"\.").replace('*', ".*"));
+                let regex_pattern = wildcard_regex(line);
                 match Regex::new(&regex_pattern) {
-                    Ok(_) => {
-                        all_patterns.push(format!("(?:{regex_pattern})"));
+                    Ok(re) => {
+                        manager.patterns.push((line.to_string(), re));
                         wildcard_count += 1;
                     }
                     Err(e) => {
                         warn!("Invalid wildcard on line {}: {line} - {e}", line_num + 1);
+                        manager.invalid_entries += 1;
                     }
                 }
                 continue;
             }
 
+            // Exact-only domain: =domain
+            if let Some(rest) = line.strip_prefix('=') {
+                let domain = normalize_domain(rest.trim());
+                if validate_domain(&domain) {
+                    manager.exact_only_domains.insert(domain);
+                    exact_count += 1;
+                }
+                continue;
+            }
+
             // Exact domain
             let domain = normalize_domain(line);
             if validate_domain(&domain) {
@@ -87,11 +251,17 @@ impl WhitelistManager {
             }
         }
 
-        // Build combined regex for wildcard and regex patterns
-        if !all_patterns.is_empty() {
-            match Regex::new(&all_patterns.join("|")) {
-                Ok(re) => manager.combined_pattern = Some(re),
-                Err(_) => warn!("Failed to compile combined whitelist pattern"),
+        // Every pattern here already compiled on its own, so a failure can
+        // only be the set's size limit; matching then falls back per pattern.
+        if !manager.patterns.is_empty() {
+            match RegexSetBuilder::new(manager.patterns.iter().map(|(_, re)| re.as_str()))
+                .dfa_size_limit(PATTERN_SET_DFA_LIMIT)
+                .build()
+            {
+                Ok(set) => manager.pattern_set = Some(set),
+                Err(e) => warn!(
+                    "Whitelist patterns too large to match together, checking them one by one: {e}"
+                ),
             }
         }
 
@@ -106,70 +276,138 @@ impl WhitelistManager {
         manager
     }
 
-    /// Check if domain is a subdomain of any whitelisted exact domain.
-    /// Zero-allocation: iterates through dot positions and checks suffixes.
-    fn check_subdomain(&self, domain: &str) -> bool {
-        let mut start = 0;
-        while let Some(dot_pos) = domain[start..].find('.') {
-            start += dot_pos + 1;
-            if self.exact_domains.contains(&domain[start..]) {
-                return true;
-            }
-        }
-        false
+    /// Set how many subdomains a single entry may remove before a warning naming
+    /// it is logged. 0 disables the check.
+    pub fn with_broad_entry_threshold(mut self, threshold: usize) -> Self {
+        self.broad_entry_threshold = threshold;
+        self
     }
 
-    pub fn filter_domains(&self, domains: &HashSet<String>) -> (HashSet<String>, usize) {
-        if self.exact_domains.is_empty() && self.combined_pattern.is_none() {
-            return (domains.clone(), 0);
-        }
+    /// Number of regex and wildcard entries that failed to compile and were skipped.
+    pub fn invalid_entries(&self) -> usize {
+        self.invalid_entries
+    }
 
-        let mut filtered = HashSet::with_capacity(domains.len());
-        let mut removed = 0usize;
+    fn is_exact_match(&self, domain: &str) -> bool {
+        self.exact_domains.contains(domain) || self.exact_only_domains.contains(domain)
+    }
 
-        for domain in domains {
-            let mut matched = false;
+    /// Find the whitelisted exact domain that `domain` is a subdomain of, if any.
+    /// `=domain` entries are deliberately not consulted here.
+    fn check_subdomain<'a>(&self, domain: &'a str) -> Option<&'a str> {
+        parent_domains(domain).find(|parent| self.exact_domains.contains(*parent))
+    }
 
-            // Exact match (O(1) set lookup)
-            if self.exact_domains.contains(domain.as_str()) {
-                matched = true;
-            }
+    pub fn filter_domains(&self, domains: &HashSet<String>) -> (HashSet<String>, WhitelistMatches) {
+        if self.exact_domains.is_empty()
+            && self.exact_only_domains.is_empty()
+            && self.patterns.is_empty()
+        {
+            return (domains.clone(), WhitelistMatches::default());
+        }
 
-            // Subdomain match (O(k) where k = domain label count)
-            if !matched && self.enable_subdomain {
-                matched = self.check_subdomain(domain);
-            }
+        // Domains are checked in parallel; each worker keeps its own survivors,
+        // match counts and subdomain hit counts, merged once at the end.
+        let (kept, removed, subdomain_hits) = domains
+            .par_iter()
+            .fold(
+                || (Vec::new(), WhitelistMatches::default(), HashMap::new()),
+                |(mut kept, mut removed, mut hits), domain| {
+                    match self.is_whitelisted(domain, &mut hits) {
+                        Some(MatchKind::Exact) => removed.exact += 1,
+                        Some(MatchKind::Subdomain) => removed.subdomain += 1,
+                        Some(MatchKind::Pattern) => removed.pattern += 1,
+                        None => kept.push(domain.clone()),
+                    }
+                    (kept, removed, hits)
+                },
+            )
+            .reduce(
+                || (Vec::new(), WhitelistMatches::default(), HashMap::new()),
+                |(mut kept, removed, mut hits), (more_kept, more_removed, more_hits)| {
+                    kept.extend(more_kept);
+                    for (entry, count) in more_hits {
+                        *hits.entry(entry).or_default() += count;
+                    }
+                    (kept, removed.merge(more_removed), hits)
+                },
+            );
+        let filtered: HashSet<String> = kept.into_iter().collect();
 
-            // Wildcard/regex match (single combined pattern)
-            if !matched {
-                if let Some(ref re) = self.combined_pattern {
-                    matched = re.is_match(domain);
-                }
-            }
+        if removed.total() > 0 {
+            info!(
+                "Filtered {} whitelisted domains ({} exact, {} subdomain, {} pattern)",
+                removed.total(),
+                removed.exact,
+                removed.subdomain,
+                removed.pattern
+            );
+        }
+        self.warn_broad_entries(&subdomain_hits);
 
-            if matched {
-                removed += 1;
-            } else {
-                filtered.insert(domain.clone());
-            }
+        (filtered, removed)
+    }
+
+    /// Which kind of entry `domain` matches, if any, counting subdomain
+    /// matches per entry in `subdomain_hits`.
+    fn is_whitelisted<'a>(
+        &self,
+        domain: &'a str,
+        subdomain_hits: &mut HashMap<&'a str, usize>,
+    ) -> Option<MatchKind> {
+        // Exact match (O(1) set lookup)
+        if self.is_exact_match(domain) {
+            return Some(MatchKind::Exact);
         }
 
-        if removed > 0 {
-            info!("Filtered {removed} whitelisted domains");
+        // Subdomain match (O(k) where k = domain label count)
+        if self.enable_subdomain {
+            if let Some(entry) = self.check_subdomain(domain) {
+                *subdomain_hits.entry(entry).or_default() += 1;
+                return Some(MatchKind::Subdomain);
+            }
         }
 
-        (filtered, removed)
+        // Wildcard/regex match
+        let matched = match &self.pattern_set {
+            Some(set) => set.is_match(domain),
+            None => self.patterns.iter().any(|(_, re)| re.is_match(domain)),
+        };
+        matched.then_some(MatchKind::Pattern)
+    }
+
+    fn warn_broad_entries(&self, subdomain_hits: &HashMap<&str, usize>) {
+        if self.broad_entry_threshold == 0 {
+            return;
+        }
+        let mut broad: Vec<(&str, usize)> = subdomain_hits
+            .iter()
+            .filter(|(_, &count)| count > self.broad_entry_threshold)
+            .map(|(&entry, &count)| (entry, count))
+            .collect();
+        broad.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+        let mut warned = self.warned_broad_entries.lock().unwrap();
+        for (entry, count) in broad {
+            if warned.insert(entry.to_string()) {
+                warn!(
+                    "Whitelist entry '{entry}' removed {count} subdomains (threshold {}); \
+                     use '={entry}' to allow only the domain itself",
+                    self.broad_entry_threshold
+                );
+            }
+        }
     }
 
     pub fn generate_report(
         &self,
         output_file: &str,
         removed_domains: &HashSet<String>,
+        line_ending: LineEnding,
     ) -> Result<()> {
         use std::io::Write;
 
-        let file = std::fs::File::create(output_file)?;
-        let mut w = std::io::BufWriter::new(file);
+        let mut w = create_output(Path::new(output_file), line_ending)?;
 
         writeln!(w, "Whitelist Report")?;
         writeln!(w, "{}", "=".repeat(80))?;
@@ -189,9 +427,9 @@ impl WhitelistManager {
         let mut pattern = Vec::new();
 
         for domain in removed_domains {
-            if self.exact_domains.contains(domain.as_str()) {
+            if self.is_exact_match(domain) {
                 exact.push(domain.as_str());
-            } else if self.enable_subdomain && self.check_subdomain(domain) {
+            } else if self.enable_subdomain && self.check_subdomain(domain).is_some() {
                 subdomain.push(domain.as_str());
             } else {
                 pattern.push(domain.as_str());
@@ -225,6 +463,8 @@ impl WhitelistManager {
         }
 
         if !pattern.is_empty() {
+            // `*.domain` wildcards land here for the bare domain too, even
+            // with subdomain matching off.
             writeln!(w, "Pattern Matches (wildcard/regex): {}", pattern.len())?;
             for d in pattern.iter().take(100) {
                 writeln!(w, "  - {d}")?;
@@ -239,3 +479,363 @@ impl WhitelistManager {
         Ok(())
     }
 }
+
+/// `--whitelist-audit-json` output.
+#[derive(Debug, Serialize)]
+struct WhitelistAudit {
+    /// Every rule, as written in the whitelist, with the domains it removed.
+    rules: BTreeMap<String, Vec<String>>,
+    /// Rules that removed nothing this run.
+    unused: Vec<String>,
+}
+
+impl WhitelistManager {
+    /// Attribute each of `removed_domains` to the rule that removed it, checking
+    /// rules in the same order as `filter_domains`. Rules that removed nothing
+    /// are included with no domains.
+    pub fn audit(&self, removed_domains: &HashSet<String>) -> BTreeMap<String, Vec<String>> {
+        let mut rules: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for domain in &self.exact_domains {
+            rules.entry(domain.clone()).or_default();
+        }
+        for domain in &self.exact_only_domains {
+            rules.entry(format!("={domain}")).or_default();
+        }
+        for (entry, _) in &self.patterns {
+            rules.entry(entry.clone()).or_default();
+        }
+
+        for domain in removed_domains {
+            let rule = if self.exact_domains.contains(domain) {
+                Some(domain.clone())
+            } else if self.exact_only_domains.contains(domain) {
+                Some(format!("={domain}"))
+            } else if let Some(entry) = self
+                .enable_subdomain
+                .then(|| self.check_subdomain(domain))
+                .flatten()
+            {
+                Some(entry.to_string())
+            } else {
+                self.patterns
+                    .iter()
+                    .find(|(_, re)| re.is_match(domain))
+                    .map(|(entry, _)| entry.clone())
+            };
+            if let Some(rule) = rule {
+                rules.entry(rule).or_default().push(domain.clone());
+            }
+        }
+
+        for domains in rules.values_mut() {
+            domains.sort();
+        }
+        rules
+    }
+
+    /// Write the `audit` of `removed_domains` as JSON, listing unused rules
+    /// separately.
+    pub fn write_audit(&self, path: &Path, removed_domains: &HashSet<String>) -> Result<()> {
+        let rules = self.audit(removed_domains);
+        let unused: Vec<String> = rules
+            .iter()
+            .filter(|(_, domains)| domains.is_empty())
+            .map(|(rule, _)| rule.clone())
+            .collect();
+        let unused_count = unused.len();
+
+        let json = serde_json::to_string_pretty(&WhitelistAudit { rules, unused })?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Failed to write whitelist audit {}", path.display()))?;
+        info!(
+            "Whitelist audit saved to: {} ({unused_count} rules removed nothing)",
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Write `removed_domains` as a plain allowlist, one domain per line with no
+/// comments, so another Pi-hole can import it directly.
+pub fn export_allowlist(
+    path: &Path,
+    removed_domains: &HashSet<String>,
+    line_ending: LineEnding,
+) -> Result<()> {
+    use std::io::Write;
+
+    let mut sorted: Vec<&String> = removed_domains.iter().collect();
+    sorted.sort();
+
+    let mut w = create_output(path, line_ending)?;
+    for domain in sorted {
+        writeln!(w, "{domain}")?;
+    }
+    w.flush()?;
+
+    info!(
+        "Exported {} whitelisted domains to: {}",
+        removed_domains.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn domains(list: &[&str]) -> HashSet<String> {
+        list.iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn regex_entry_without_flags() {
+        assert_eq!(split_regex_entry("/^ads\\./"), Some(("^ads\\.", "")));
+        assert_eq!(compile_regex_entry("^ads", "").unwrap(), "(?:^ads)");
+    }
+
+    #[test]
+    fn regex_entry_with_flags() {
+        assert_eq!(split_regex_entry("/^ads/i"), Some(("^ads", "i")));
+        assert_eq!(compile_regex_entry("^ads", "i").unwrap(), "(?i:^ads)");
+        assert_eq!(compile_regex_entry("^ads", "ix").unwrap(), "(?ix:^ads)");
+    }
+
+    #[test]
+    fn regex_entry_rejects_unknown_flags() {
+        assert!(compile_regex_entry("^ads", "q").is_err());
+        assert!(compile_regex_entry("^ads", "ig").is_err());
+    }
+
+    #[test]
+    fn regex_entry_not_a_regex() {
+        assert_eq!(split_regex_entry("example.com"), None);
+        assert_eq!(split_regex_entry("//"), None);
+        assert_eq!(split_regex_entry("/ads/1"), None);
+        assert_eq!(split_regex_entry("/^ok/q"), None);
+        assert_eq!(split_regex_entry("/ads/tracker"), None);
+        assert_eq!(
+            split_regex_entry("/^ads/tracker\\./"),
+            Some(("^ads/tracker\\.", ""))
+        );
+        assert_eq!(
+            split_regex_entry("/^ads/tracker/U"),
+            Some(("^ads/tracker", "U"))
+        );
+    }
+
+    #[test]
+    fn counts_invalid_regex_and_wildcard_entries() {
+        let wl =
+            WhitelistManager::parse("/^ads(/\n/^ok/q\nbad(*.com\n/^fine/\n*.good.com\n", false);
+        assert_eq!(wl.invalid_entries(), 3);
+        let wl = WhitelistManager::parse("example.com\n/^ads/i\n*.good.com\n", false);
+        assert_eq!(wl.invalid_entries(), 0);
+    }
+
+    #[test]
+    fn invalid_entries_do_not_disable_other_patterns() {
+        let wl = WhitelistManager::parse("/^ads(/\nbad(*.com\n/^fine/\n*.good.com\n", false);
+        assert!(wl.pattern_set.is_some());
+        let (kept, removed) =
+            wl.filter_domains(&domains(&["fine.org", "a.good.com", "ads.net", "bad.com"]));
+        assert_eq!(removed.total(), 2);
+        assert_eq!(kept, domains(&["ads.net", "bad.com"]));
+    }
+
+    #[test]
+    fn filter_counts_removals_by_match_type() {
+        let wl = WhitelistManager::parse("example.com\n=only.org\n*.cdn.net\n/^ads\\./\n", true);
+        let (kept, removed) = wl.filter_domains(&domains(&[
+            "example.com",
+            "only.org",
+            "a.example.com",
+            "b.a.example.com",
+            "x.cdn.net",
+            "ads.site.io",
+            "kept.com",
+        ]));
+        assert_eq!(kept, domains(&["kept.com"]));
+        assert_eq!(
+            removed,
+            WhitelistMatches {
+                exact: 2,
+                subdomain: 2,
+                pattern: 2
+            }
+        );
+        assert_eq!(removed.total(), 6);
+    }
+
+    #[test]
+    fn reports_invalid_utf8_lines() {
+        let bytes = b"example.com\nbad\xff.com\r\nok.com\n\xfe\n";
+        assert_eq!(invalid_utf8_lines(bytes), [2, 4]);
+        assert!(invalid_utf8_lines(b"example.com\n").is_empty());
+
+        let wl = WhitelistManager::parse(&String::from_utf8_lossy(bytes), false);
+        let (kept, removed) = wl.filter_domains(&domains(&["example.com", "ok.com", "x.com"]));
+        assert_eq!(removed.total(), 2);
+        assert!(kept.contains("x.com"));
+    }
+
+    #[test]
+    fn exact_only_entry_skips_subdomains() {
+        let wl = WhitelistManager::parse("=example.com\nsafe.com\n", true);
+        let (kept, removed) = wl.filter_domains(&domains(&[
+            "example.com",
+            "phish.example.com",
+            "safe.com",
+            "www.safe.com",
+        ]));
+        assert_eq!(removed.total(), 3);
+        assert!(kept.contains("phish.example.com"));
+    }
+
+    #[test]
+    fn check_subdomain_names_matching_entry() {
+        let wl = WhitelistManager::parse("googleapis.com\n", true);
+        assert_eq!(
+            wl.check_subdomain("maps.googleapis.com"),
+            Some("googleapis.com")
+        );
+        assert_eq!(wl.check_subdomain("googleapis.com"), None);
+        assert_eq!(wl.check_subdomain("notgoogleapis.com"), None);
+    }
+
+    #[test]
+    fn broad_entry_is_warned_once() {
+        let wl = WhitelistManager::parse("googleapis.com\nsmall.com\n", true)
+            .with_broad_entry_threshold(2);
+        let set = domains(&[
+            "a.googleapis.com",
+            "b.googleapis.com",
+            "c.googleapis.com",
+            "a.small.com",
+        ]);
+        let (kept, removed) = wl.filter_domains(&set);
+        assert!(kept.is_empty());
+        assert_eq!(removed.total(), 4);
+        let warned = wl.warned_broad_entries.lock().unwrap();
+        assert_eq!(*warned, HashSet::from(["googleapis.com".to_string()]));
+    }
+
+    #[test]
+    fn export_allowlist_writes_sorted_plain_domains() {
+        let wl = WhitelistManager::parse("cdn.net\n", true);
+        let all = domains(&["b.cdn.net", "a.cdn.net", "ads.example.com"]);
+        let (kept, _) = wl.filter_domains(&all);
+        let removed: HashSet<String> = all.difference(&kept).cloned().collect();
+
+        let dir = TempDir::new("allow");
+        let path = dir.join("allowlist.txt");
+        export_allowlist(&path, &removed, LineEnding::Lf).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(written, "a.cdn.net\nb.cdn.net\n");
+    }
+
+    #[test]
+    fn load_required_fails_on_missing_file() {
+        let dir = TempDir::new("whitelist");
+        let missing = dir.join("missing.txt");
+        let err = WhitelistManager::load_required(missing.to_str().unwrap(), true)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not found"));
+
+        let present = dir.join("whitelist.txt");
+        std::fs::write(&present, "example.com\n").unwrap();
+        let wl = WhitelistManager::load_required(present.to_str().unwrap(), true).unwrap();
+        assert!(wl.is_exact_match("example.com"));
+    }
+
+    #[test]
+    fn leading_wildcard_covers_apex_and_subdomains() {
+        let all = domains(&[
+            "x.com",
+            "a.x.com",
+            "a.b.x.com",
+            "notx.com",
+            "x.com.evil.net",
+        ]);
+        for subdomains in [true, false] {
+            let wl = WhitelistManager::parse("*.x.com\n", subdomains);
+            let (kept, removed) = wl.filter_domains(&all);
+            assert_eq!(removed.total(), 3);
+            assert_eq!(kept, domains(&["notx.com", "x.com.evil.net"]));
+        }
+    }
+
+    #[test]
+    fn exact_entry_covers_subdomains_only_with_subdomain_matching() {
+        let all = domains(&["x.com", "a.x.com", "a.b.x.com", "notx.com"]);
+
+        let (kept, _) = WhitelistManager::parse("x.com\n", true).filter_domains(&all);
+        assert_eq!(kept, domains(&["notx.com"]));
+
+        let (kept, _) = WhitelistManager::parse("x.com\n", false).filter_domains(&all);
+        assert_eq!(kept, domains(&["a.x.com", "a.b.x.com", "notx.com"]));
+    }
+
+    #[test]
+    fn inner_wildcards_are_not_widened() {
+        let wl = WhitelistManager::parse("ads.*\n*analytics*\n", false);
+        let all = domains(&["ads.example.com", "ads", "myanalytics.com", "example.com"]);
+        let (kept, _) = wl.filter_domains(&all);
+        assert_eq!(kept, domains(&["ads", "example.com"]));
+    }
+
+    #[test]
+    fn audit_attributes_removals_and_lists_unused_rules() {
+        let wl = WhitelistManager::parse(
+            "cdn.net\n=exact.org\n*.track.io\n/^ads[0-9]+\\./\nunused.com\n",
+            true,
+        );
+        let all = domains(&[
+            "cdn.net",
+            "a.cdn.net",
+            "exact.org",
+            "x.track.io",
+            "ads1.example.com",
+            "kept.com",
+        ]);
+        let (kept, _) = wl.filter_domains(&all);
+        let removed: HashSet<String> = all.difference(&kept).cloned().collect();
+
+        let audit = wl.audit(&removed);
+        assert_eq!(audit["cdn.net"], ["a.cdn.net", "cdn.net"]);
+        assert_eq!(audit["=exact.org"], ["exact.org"]);
+        assert_eq!(audit["*.track.io"], ["x.track.io"]);
+        assert_eq!(audit["/^ads[0-9]+\\./"], ["ads1.example.com"]);
+        assert!(audit["unused.com"].is_empty());
+        assert_eq!(audit.values().map(Vec::len).sum::<usize>(), removed.len());
+    }
+
+    #[test]
+    fn filter_applies_regex_flags() {
+        let wl = WhitelistManager::parse("/^ADS\\./i\n/^UPPER\\./\n", false);
+        let (kept, removed) =
+            wl.filter_domains(&domains(&["ads.example.com", "upper.example.com"]));
+        assert_eq!(removed.total(), 1);
+        assert!(kept.contains("upper.example.com"));
+    }
+
+    #[test]
+    fn filter_counts_hits_across_parallel_chunks() {
+        let wl = WhitelistManager::parse("keep.me\ncdn.net\n", true).with_broad_entry_threshold(0);
+        let set: HashSet<String> = (0..10_000)
+            .map(|i| match i % 3 {
+                0 => format!("a{i}.cdn.net"),
+                1 => format!("ads{i}.example.com"),
+                _ => "keep.me".to_string(),
+            })
+            .collect();
+        let (kept, removed) = wl.filter_domains(&set);
+        assert_eq!(removed.total(), set.len() - kept.len());
+        assert!(kept.iter().all(|d| d.starts_with("ads")));
+        assert_eq!(kept.len(), 3333);
+    }
+}